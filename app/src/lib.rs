@@ -1,7 +1,19 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::js_sys::ArrayBuffer;
-use web_sys::{Blob, CanvasRenderingContext2d, HtmlCanvasElement, ImageData, WebSocket};
+use web_sys::{
+    BinaryType, Blob, CanvasRenderingContext2d, HtmlCanvasElement, HtmlElement, HtmlInputElement,
+    HtmlSelectElement, ImageData, MessageEvent, WebSocket,
+};
+
+/// Marker byte at the start of a delta frame, see `PixelMap::encode_tiles`
+const DELTA_MARKER: u8 = b'D';
+
+/// How often the time range of the history is refreshed, in ms
+const HISTORY_REFRESH: i32 = 30_000;
 
 #[wasm_bindgen(start)]
 async fn main() {
@@ -9,17 +21,19 @@ async fn main() {
 
     web_sys::console::log_1(&"Hello from Rust!".into());
 
-    let binding = web_sys::window()
-        .unwrap()
-        .document()
-        .unwrap()
-        .get_element_by_id("canvas")
-        .unwrap();
+    let document = web_sys::window().unwrap().document().unwrap();
+    let binding = document.get_element_by_id("canvas").unwrap();
 
-    let el: &HtmlCanvasElement = binding.dyn_ref::<HtmlCanvasElement>().unwrap();
-    let mut ctx: CanvasRenderingContext2d =
+    let el: HtmlCanvasElement = binding.dyn_into::<HtmlCanvasElement>().unwrap();
+    let ctx: CanvasRenderingContext2d =
         el.get_context("2d").unwrap().unwrap().dyn_into().unwrap();
-    let e = JsFuture::from(web_sys::window().unwrap().fetch_with_str("/api/canvas"))
+    // Other canvases than the main one are picked with `?canvas=<name>`
+    let canvas = canvas_name();
+    let (canvas_path, live_path) = match &canvas {
+        Some(name) => (format!("/api/canvas/{}", name), format!("/api/ws/{}", name)),
+        None => ("/api/canvas".to_string(), "/api/ws".to_string()),
+    };
+    let e = JsFuture::from(web_sys::window().unwrap().fetch_with_str(&canvas_path))
         .await
         .unwrap();
     let res = e.unchecked_into::<web_sys::Response>();
@@ -51,62 +65,263 @@ async fn main() {
         ctx.put_image_data(&img, 0.0, 0.0).unwrap();
     }
 
-    let ws = match web_sys::window()
-        .unwrap()
-        .location()
-        .protocol()
+    let timeline: HtmlInputElement = document
+        .get_element_by_id("timeline")
         .unwrap()
-        .as_str()
-    {
-        "http:" => WebSocket::new(
-            &("ws://".to_owned()
-                + &*web_sys::window().unwrap().location().host().unwrap()
-                + "/api/ws"),
-        ),
-        "https:" | _ => WebSocket::new(
-            &("wss://".to_owned()
-                + &*web_sys::window().unwrap().location().host().unwrap()
-                + "/api/ws"),
-        ),
-    }
+        .dyn_into()
         .unwrap();
-    let mut closure_ws = ws.clone();
-
-    //let ev = EventSource::new("/api/pixels").unwrap();
-    let closure = Closure::wrap(Box::new(move |e: web_sys::Event| {
-        let dimensions = &dimensions;
-        let dimensions = dimensions.clone();
-        let closure_ws = &mut closure_ws;
-        let mut cl_ws = closure_ws.clone();
-        let e = e.unchecked_into::<web_sys::MessageEvent>();
-        let blob = e.data().unchecked_into::<Blob>();
-        let ctx = &mut ctx;
-        let mut ctx = ctx.clone();
-        let cl = Closure::wrap(Box::new(move |arr| {
-            let dimensions = &dimensions;
-            let cl_ws = &mut cl_ws;
-            let vec = js_sys::Uint8Array::new(&arr).to_vec();
+    let time_label = document.get_element_by_id("time").unwrap();
+
+    // Replays send the time of each frame as text before the frame itself
+    let on_message = {
+        let timeline = timeline.clone();
+        let time_label = time_label.clone();
+        Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
+            if let Some(time) = e.data().as_string() {
+                if let Ok(time) = time.parse::<f64>() {
+                    timeline.set_value_as_number(time);
+                    time_label.set_text_content(Some(&format_time(time)));
+                }
+                return;
+            }
+            let vec = js_sys::Uint8Array::new(&e.data()).to_vec();
             let data = fdeflate::decompress_to_vec(&vec).unwrap();
-            if data != vec![0u8] {
+            if data == vec![0u8] {
+                // web_sys::console::log_1(&JsValue::from_str("Got null-byte. Not changing anything"))
+            } else if data[0] == DELTA_MARKER {
+                put_delta(&ctx, &data);
+            } else {
+                let (qoi, pixels) = rapid_qoi::Qoi::decode_alloc(&data).unwrap();
+                if (el.width(), el.height()) != (qoi.width, qoi.height) {
+                    el.set_width(qoi.width);
+                    el.set_height(qoi.height);
+                }
                 let img = ImageData::new_with_u8_clamped_array_and_sh(
-                    wasm_bindgen::Clamped(&*rapid_qoi::Qoi::decode_alloc(&*data).unwrap().1),
-                    dimensions[0],
-                    dimensions[1],
+                    wasm_bindgen::Clamped(&*pixels),
+                    qoi.width,
+                    qoi.height,
                 );
-                let ctx = &mut ctx;
-                let ctx = ctx.clone();
                 ctx.put_image_data(&img.unwrap(), 0.0, 0.0).unwrap();
-            } else {
-                // web_sys::console::log_1(&JsValue::from_str("Got null-byte. Not changing anything"))
             }
-            cl_ws.send_with_str("update").unwrap();
-        }) as Box<dyn FnMut(_)>);
-        let _ = blob.array_buffer().then(&cl);
-        std::mem::forget(cl);
-    }) as Box<dyn FnMut(_)>);
+        })
+    };
+    let viewer = Rc::new(Viewer {
+        ws: RefCell::new(None),
+        on_message,
+        replaying: Cell::new(false),
+    });
+    viewer.connect(&live_path);
+
+    // The history only follows the main canvas
+    if canvas.is_none() {
+        setup_history(viewer, timeline, time_label).await;
+    }
+}
+
+/// The canvas named in the query of the page, if any
+fn canvas_name() -> Option<String> {
+    let search = web_sys::window().unwrap().location().search().unwrap_or_default();
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("canvas="))
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// The WebSocket the canvas is currently drawn from, either the live view
+/// or a replay of the history
+struct Viewer {
+    ws: RefCell<Option<WebSocket>>,
+    on_message: Closure<dyn FnMut(MessageEvent)>,
+    replaying: Cell<bool>,
+}
+
+impl Viewer {
+    /// Replaces the current WebSocket with one to `path`
+    fn connect(&self, path: &str) {
+        if let Some(ws) = self.ws.borrow_mut().take() {
+            ws.set_onmessage(None);
+            let _ = ws.close();
+        }
+        let ws = match web_sys::window()
+            .unwrap()
+            .location()
+            .protocol()
+            .unwrap()
+            .as_str()
+        {
+            "http:" => WebSocket::new(
+                &("ws://".to_owned()
+                    + &*web_sys::window().unwrap().location().host().unwrap()
+                    + path),
+            ),
+            "https:" | _ => WebSocket::new(
+                &("wss://".to_owned()
+                    + &*web_sys::window().unwrap().location().host().unwrap()
+                    + path),
+            ),
+        }
+            .unwrap();
+        // Frames are pushed by the server and have to be applied in order, so take
+        // them as ArrayBuffers directly instead of going through async Blob reads
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        ws.set_onmessage(Some(self.on_message.as_ref().unchecked_ref()));
+        *self.ws.borrow_mut() = Some(ws);
+        self.replaying.set(path.starts_with("/api/replay"));
+    }
+}
+
+/// Shows the replay controls if the server keeps a history and wires them up
+async fn setup_history(viewer: Rc<Viewer>, timeline: HtmlInputElement, time_label: web_sys::Element) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let controls: HtmlElement = document
+        .get_element_by_id("history")
+        .unwrap()
+        .dyn_into()
+        .unwrap();
+    let speed: HtmlSelectElement = document
+        .get_element_by_id("speed")
+        .unwrap()
+        .dyn_into()
+        .unwrap();
+    let live = document.get_element_by_id("live").unwrap();
+
+    if !update_range(&timeline).await {
+        return;
+    }
+    controls.set_hidden(false);
+    timeline.set_value_as_number(timeline.max().parse().unwrap_or(0.0));
+
+    let replay = {
+        let viewer = Rc::clone(&viewer);
+        let timeline = timeline.clone();
+        let speed = speed.clone();
+        Rc::new(move || {
+            viewer.connect(&format!(
+                "/api/replay?start={}&speed={}",
+                timeline.value_as_number() as u64,
+                speed.value()
+            ));
+        })
+    };
+
+    // Scrubbing only shows the time, the replay starts once the slider is let go
+    let on_input = {
+        let timeline = timeline.clone();
+        let time_label = time_label.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            time_label.set_text_content(Some(&format_time(timeline.value_as_number())));
+        })
+    };
+    timeline
+        .add_event_listener_with_callback("input", on_input.as_ref().unchecked_ref())
+        .unwrap();
+    on_input.forget();
+
+    let on_change = {
+        let replay = Rc::clone(&replay);
+        Closure::<dyn FnMut()>::new(move || replay())
+    };
+    timeline
+        .add_event_listener_with_callback("change", on_change.as_ref().unchecked_ref())
+        .unwrap();
+    on_change.forget();
+
+    let on_speed = {
+        let viewer = Rc::clone(&viewer);
+        Closure::<dyn FnMut()>::new(move || {
+            if viewer.replaying.get() {
+                replay();
+            }
+        })
+    };
+    speed
+        .add_event_listener_with_callback("change", on_speed.as_ref().unchecked_ref())
+        .unwrap();
+    on_speed.forget();
 
-    ws.add_event_listener_with_callback("message", closure.as_ref().unchecked_ref())
+    let on_live = {
+        let viewer = Rc::clone(&viewer);
+        let timeline = timeline.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            viewer.connect("/api/ws");
+            timeline.set_value_as_number(timeline.max().parse().unwrap_or(0.0));
+            time_label.set_text_content(Some("Live"));
+        })
+    };
+    live.add_event_listener_with_callback("click", on_live.as_ref().unchecked_ref())
         .unwrap();
+    on_live.forget();
 
-    closure.forget();
+    // The history keeps growing, keep the end of the slider up to date
+    loop {
+        let promise = js_sys::Promise::new(&mut |resolve, _| {
+            web_sys::window()
+                .unwrap()
+                .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, HISTORY_REFRESH)
+                .unwrap();
+        });
+        let _ = JsFuture::from(promise).await;
+        update_range(&timeline).await;
+        if !viewer.replaying.get() {
+            timeline.set_value_as_number(timeline.max().parse().unwrap_or(0.0));
+        }
+    }
+}
+
+/// Sets the bounds of the timeline to the time range of the history.
+/// Returns false if the server doesn't keep a history.
+async fn update_range(timeline: &HtmlInputElement) -> bool {
+    let res = match JsFuture::from(web_sys::window().unwrap().fetch_with_str("/api/history")).await {
+        Ok(res) => res.unchecked_into::<web_sys::Response>(),
+        Err(_) => return false,
+    };
+    if !res.ok() {
+        return false;
+    }
+    let range = match JsFuture::from(res.json().unwrap()).await {
+        Ok(range) => range,
+        Err(_) => return false,
+    };
+    let get = |key: &str| {
+        js_sys::Reflect::get(&range, &key.into())
+            .ok()
+            .and_then(|value| value.as_f64())
+    };
+    match (get("start"), get("end")) {
+        (Some(start), Some(end)) => {
+            timeline.set_min(&start.to_string());
+            timeline.set_max(&end.to_string());
+            true
+        }
+        _ => false,
+    }
+}
+
+fn format_time(ms: f64) -> String {
+    js_sys::Date::new(&JsValue::from_f64(ms))
+        .to_locale_time_string("default")
+        .into()
+}
+
+/// Draws the tiles of a delta frame onto the canvas, leaving the rest as is.
+/// Format: [u8: 'D'][u32: count] then per tile [u16: x][u16: y][u16: w][u16: h][w * h * rgba]
+fn put_delta(ctx: &CanvasRenderingContext2d, data: &[u8]) {
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]) as u32;
+    let count = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    let mut pos = 5;
+    for _ in 0..count {
+        let (x, y, w, h) = (u16_at(pos), u16_at(pos + 2), u16_at(pos + 4), u16_at(pos + 6));
+        pos += 8;
+        let len = (w * h * 4) as usize;
+        let img = ImageData::new_with_u8_clamped_array_and_sh(
+            wasm_bindgen::Clamped(&data[pos..pos + len]),
+            w,
+            h,
+        )
+            .unwrap();
+        ctx.put_image_data(&img, x as f64, y as f64).unwrap();
+        pos += len;
+    }
 }
@@ -0,0 +1,140 @@
+//! Measures how many pixels per second the server takes in through binary mode.
+//!
+//! Starts the pixelrust binary on free local ports, streams full canvas frames
+//! of binary records at it and waits until the last pixel has been applied.
+//!
+//! Run with `cargo bench --bench binary`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WIDTH: u16 = 1280;
+const HEIGHT: u16 = 720;
+const FRAMES: u32 = 10;
+const RUNS: u32 = 3;
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_server() -> (Server, String) {
+    let listen = format!("127.0.0.1:{}", free_port());
+    let snapshot = std::env::temp_dir().join(format!("pixelrust-bench-{}.qoi", std::process::id()));
+    let _ = std::fs::remove_file(&snapshot);
+    let child = Command::new(env!("CARGO_BIN_EXE_pixelrust"))
+        .arg("--listen")
+        .arg(&listen)
+        .arg("--http-listen")
+        .arg(format!("127.0.0.1:{}", free_port()))
+        .arg("--snapshot")
+        .arg(&snapshot)
+        .arg("--width")
+        .arg(WIDTH.to_string())
+        .arg("--height")
+        .arg(HEIGHT.to_string())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start pixelrust");
+    let server = Server(child);
+    for _ in 0..100 {
+        if TcpStream::connect(&listen).is_ok() {
+            return (server, listen);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("pixelrust did not start listening on {}", listen);
+}
+
+fn record(x: u16, y: u16, rgba: u32) -> [u8; 8] {
+    let mut record = [0; 8];
+    record[0..2].copy_from_slice(&x.to_le_bytes());
+    record[2..4].copy_from_slice(&y.to_le_bytes());
+    record[4..8].copy_from_slice(&rgba.to_le_bytes());
+    record
+}
+
+/// One record per pixel, in a different color per frame
+fn payload(run: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(WIDTH as usize * HEIGHT as usize * 8 * FRAMES as usize);
+    for frame in 0..FRAMES {
+        let shade = (run * FRAMES + frame) as u8;
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let rgba = u32::from_be_bytes([x as u8, y as u8, shade, 0xff]);
+                payload.extend_from_slice(&record(x, y, rgba));
+            }
+        }
+    }
+    payload
+}
+
+fn wait_for_pixel(listen: &str, expected: &str) {
+    let stream = TcpStream::connect(listen).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut line = String::new();
+    loop {
+        writer.write_all(b"PX 0 0\n").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line.trim() == expected {
+            return;
+        }
+    }
+}
+
+fn main() {
+    let (_server, listen) = start_server();
+    let pixels = WIDTH as u64 * HEIGHT as u64 * FRAMES as u64;
+    let mut rates = Vec::new();
+
+    for run in 0..RUNS {
+        let mut payload = payload(run);
+        // Finish with a marker pixel to know when everything has been applied
+        let marker = 0x0102_03ff ^ (run << 8);
+        payload.extend_from_slice(&record(0, 0, marker));
+
+        let mut stream = TcpStream::connect(&listen).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream.write_all(b"BIN\n").unwrap();
+        let mut ack = [0; 3];
+        stream.read_exact(&mut ack).unwrap();
+
+        let start = Instant::now();
+        stream.write_all(&payload).unwrap();
+        wait_for_pixel(&listen, &format!("PX 0 0 {:08x}", marker));
+        let elapsed = start.elapsed();
+
+        let rate = pixels as f64 / elapsed.as_secs_f64();
+        println!(
+            "run {}: {} pixels in {:.3?} = {:.2} Mpx/s",
+            run + 1,
+            pixels,
+            elapsed,
+            rate / 1e6
+        );
+        rates.push(rate);
+    }
+
+    rates.sort_by(|a, b| a.total_cmp(b));
+    println!(
+        "binary mode median: {:.2} Mpx/s",
+        rates[rates.len() / 2] / 1e6
+    );
+}
@@ -0,0 +1,293 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::connections::{Connections, Rejection};
+
+/// How often the list files are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A single IP address or a CIDR range like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(net: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid IP or CIDR range '{}'", net);
+        let (addr, prefix) = match net.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (net, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.addr, self.prefix) {
+            (IpAddr::V4(addr), 32) => write!(f, "{}", addr),
+            (IpAddr::V6(addr), 128) => write!(f, "{}", addr),
+            (addr, prefix) => write!(f, "{}/{}", addr, prefix),
+        }
+    }
+}
+
+/// Decides who may connect. IPs on the ban list are turned away and, with an
+/// allow list, so is everyone who isn't on it. Both lists are files with one
+/// IP or CIDR range per line and `#` comments, which are reloaded when they
+/// change.
+pub(crate) struct AccessList {
+    ban_file: Option<PathBuf>,
+    allow_file: Option<PathBuf>,
+    lists: RwLock<Lists>,
+}
+
+#[derive(Default)]
+struct Lists {
+    banned: Vec<IpNet>,
+    allowed: Option<Vec<IpNet>>,
+    // Modification times of the files when they were last read
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl AccessList {
+    /// Reads the lists. A missing ban list starts out empty, it is created
+    /// with the first ban.
+    pub fn open(ban_file: Option<&str>, allow_file: Option<&str>) -> Result<AccessList, String> {
+        let access = AccessList {
+            ban_file: ban_file.map(PathBuf::from),
+            allow_file: allow_file.map(PathBuf::from),
+            lists: RwLock::new(Lists::default()),
+        };
+        access.reload(true)?;
+        Ok(access)
+    }
+
+    /// Whether `ip` may connect
+    pub fn check(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let lists = self.lists.read().unwrap();
+        if lists.banned.iter().any(|net| net.contains(ip)) {
+            return Err(Rejection::Banned);
+        }
+        match &lists.allowed {
+            Some(allowed) if !allowed.iter().any(|net| net.contains(ip)) => {
+                Err(Rejection::NotAllowed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The banned and, in allow list mode, the allowed ranges
+    pub fn lists(&self) -> (Vec<IpNet>, Option<Vec<IpNet>>) {
+        let lists = self.lists.read().unwrap();
+        (lists.banned.clone(), lists.allowed.clone())
+    }
+
+    /// Adds `net` to the ban list and its file. Returns false if it is
+    /// already on it.
+    pub fn ban(&self, net: IpNet) -> io::Result<bool> {
+        let mut lists = self.lists.write().unwrap();
+        if lists.banned.contains(&net) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.ban_file {
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", net)?;
+            lists.modified.0 = modified(path);
+        }
+        lists.banned.push(net);
+        Ok(true)
+    }
+
+    /// Removes `net` from the ban list and its file. Returns false if it
+    /// wasn't on it.
+    pub fn unban(&self, net: IpNet) -> io::Result<bool> {
+        let mut lists = self.lists.write().unwrap();
+        if !lists.banned.contains(&net) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.ban_file {
+            // Keeps comments and everything else as it was
+            let content = fs::read_to_string(path)?;
+            let kept: String = content
+                .lines()
+                .filter(|line| parse_line(line) != Some(Ok(net)))
+                .map(|line| format!("{}\n", line))
+                .collect();
+            fs::write(path, kept)?;
+            lists.modified.0 = modified(path);
+        }
+        lists.banned.retain(|banned| *banned != net);
+        Ok(true)
+    }
+
+    /// Reads the files again if they changed since the last time, or always
+    /// with `force`. Returns whether anything was read. On errors the old
+    /// lists stay in place.
+    pub fn reload(&self, force: bool) -> Result<bool, String> {
+        let ban_modified = self.ban_file.as_deref().and_then(modified);
+        let allow_modified = self.allow_file.as_deref().and_then(modified);
+        if !force && self.lists.read().unwrap().modified == (ban_modified, allow_modified) {
+            return Ok(false);
+        }
+        let banned = match &self.ban_file {
+            Some(path) if path.exists() => read_list(path)?,
+            Some(_) => Vec::new(),
+            // Without a file the bans only live in memory, there is nothing to reload
+            None => self.lists.read().unwrap().banned.clone(),
+        };
+        let allowed = match &self.allow_file {
+            Some(path) => Some(read_list(path)?),
+            None => None,
+        };
+        *self.lists.write().unwrap() = Lists {
+            banned,
+            allowed,
+            modified: (ban_modified, allow_modified),
+        };
+        Ok(true)
+    }
+
+    /// Reloads the lists every `interval` once they change and closes the
+    /// connections that aren't allowed anymore
+    pub async fn run(self: Arc<Self>, connections: Arc<Connections>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes right away
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let access = Arc::clone(&self);
+            match tokio::task::spawn_blocking(move || access.reload(false)).await {
+                Ok(Ok(true)) => {
+                    let (banned, allowed) = self.lists();
+                    let closed = connections.disconnect_where(|ip| self.check(ip).is_err());
+                    println!(
+                        "Reloaded the access lists: {} banned, {} allowed, closed {} connections",
+                        banned.len(),
+                        allowed.map_or("everyone".to_string(), |allowed| allowed.len().to_string()),
+                        closed
+                    );
+                }
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => println!("Failed to reload the access lists: {}", e),
+                Err(e) => println!("Access list task failed: {}", e),
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// One entry per line, everything after a `#` is a comment
+fn parse_line(line: &str) -> Option<Result<IpNet, String>> {
+    let entry = line.split('#').next().unwrap_or("").trim();
+    (!entry.is_empty()).then(|| entry.parse())
+}
+
+fn read_list(path: &Path) -> Result<Vec<IpNet>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i, parse_line(line)?)))
+        .map(|(i, net)| net.map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ranges() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        // IPv4 clients of a dual stack listener show up as mapped addresses
+        assert!(net.contains(ip("::ffff:10.1.0.1")));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("1.2.3.4")));
+        let single: IpNet = "2001:db8::1".parse().unwrap();
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+        assert!("2001:db8::/32".parse::<IpNet>().unwrap().contains(ip("2001:db8:ff::1")));
+        assert!(!net.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!("10.0.0.1".parse::<IpNet>().unwrap().to_string(), "10.0.0.1");
+        assert_eq!("10.0.0.0/8".parse::<IpNet>().unwrap().to_string(), "10.0.0.0/8");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+        assert_eq!(parse_line("  # just a comment"), None);
+        assert_eq!(
+            parse_line("192.0.2.7 # spammer"),
+            Some(Ok("192.0.2.7".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn bans_persist_in_the_file() {
+        let path = std::env::temp_dir().join(format!("pixelrust-bans-{}", std::process::id()));
+        fs::write(&path, "# bans\n10.0.0.0/8\n").unwrap();
+        let access = AccessList::open(path.to_str(), None).unwrap();
+        let ip = "10.3.3.3".parse().unwrap();
+        assert_eq!(access.check(ip), Err(Rejection::Banned));
+
+        let net = "192.0.2.1".parse().unwrap();
+        assert!(access.ban(net).unwrap());
+        assert!(!access.ban(net).unwrap());
+        assert!(access.unban("10.0.0.0/8".parse().unwrap()).unwrap());
+        assert_eq!(access.check(ip), Ok(()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# bans\n192.0.2.1\n");
+
+        let reopened = AccessList::open(path.to_str(), None).unwrap();
+        assert_eq!(reopened.check("192.0.2.1".parse().unwrap()), Err(Rejection::Banned));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bans_without_a_file_survive_reloads() {
+        let access = AccessList::open(None, None).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(access.ban("192.0.2.0/24".parse().unwrap()).unwrap());
+        assert!(access.reload(true).unwrap());
+        assert_eq!(access.check(ip), Err(Rejection::Banned));
+    }
+}
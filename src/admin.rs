@@ -0,0 +1,478 @@
+use std::sync::Arc;
+
+use crate::access::{AccessList, IpNet};
+use crate::canvases::{Canvases, NamedCanvas};
+use crate::color::Color;
+use crate::connections::Connections;
+use crate::http::{Request, Response};
+use crate::image::{self, Fit};
+
+/// Every admin route with the method it takes
+const ROUTES: &[(&str, &str)] = &[
+    ("/admin/resize", "POST"),
+    ("/admin/clear", "POST"),
+    ("/admin/fill", "POST"),
+    ("/admin/load", "POST"),
+    ("/admin/snapshot", "POST"),
+    ("/admin/connections", "GET"),
+    ("/admin/disconnect", "POST"),
+    ("/admin/ban", "POST"),
+    ("/admin/unban", "POST"),
+    ("/admin/access", "GET"),
+    ("/admin/reload-access", "POST"),
+    ("/admin/read-only", "POST"),
+];
+
+/// Maintenance endpoints below `/admin`. Every request has to carry the admin
+/// token as `Authorization: Bearer <token>`.
+pub(crate) struct Admin {
+    token: String,
+    canvases: Arc<Canvases>,
+    connections: Arc<Connections>,
+    access: Arc<AccessList>,
+    max_upload: usize,
+}
+
+impl Admin {
+    pub fn new(
+        token: String,
+        canvases: Arc<Canvases>,
+        connections: Arc<Connections>,
+        access: Arc<AccessList>,
+        max_upload: usize,
+    ) -> Admin {
+        Admin {
+            token,
+            canvases,
+            connections,
+            access,
+            max_upload,
+        }
+    }
+
+    /// How large the body of `request` may be. Its body is only read once the
+    /// token checks out, anyone else is answered right away.
+    pub fn max_body(&self, request: &Request) -> Result<usize, Response> {
+        match self.authorized(request) {
+            true => Ok(self.max_upload),
+            false => Err(unauthorized()),
+        }
+    }
+
+    pub async fn respond(&self, request: &Request, route: &str) -> Response {
+        if !self.authorized(request) {
+            return unauthorized();
+        }
+        let Some(&(_, method)) = ROUTES.iter().find(|(path, _)| *path == route) else {
+            return Response::new(404).text("Not Found");
+        };
+        if request.method != method {
+            return Response::new(405)
+                .header("Allow", method)
+                .text("Method Not Allowed");
+        }
+        let result = match route {
+            "/admin/resize" => self.resize(request).await,
+            "/admin/clear" => self.fill(request, Some(Color::black())).await,
+            "/admin/fill" => self.fill(request, None).await,
+            "/admin/load" => self.load(request).await,
+            "/admin/snapshot" => self.snapshot(request).await,
+            "/admin/connections" => return self.list_connections(),
+            "/admin/disconnect" => self.disconnect(request),
+            "/admin/ban" => self.ban(request),
+            "/admin/unban" => self.unban(request),
+            "/admin/access" => return self.list_access(),
+            "/admin/reload-access" => self.reload_access().await,
+            _ => self.read_only(request),
+        };
+        match result {
+            Ok(message) => {
+                println!("Admin: {}", message);
+                Response::new(200).text(&message)
+            }
+            Err(e) => Response::new(400).text(&e),
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // Compares every byte, so the time taken doesn't tell how much of a guess was right
+        token.len() == self.token.len()
+            && token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// The canvas named by the `canvas` parameter, the main one without it
+    fn canvas(&self, request: &Request) -> Result<&Arc<NamedCanvas>, String> {
+        match request.query_param("canvas") {
+            Some(name) => self
+                .canvases
+                .get(&name)
+                .ok_or(format!("Unknown canvas '{}'", name)),
+            None => Ok(self.canvases.main()),
+        }
+    }
+
+    /// `POST /admin/resize?width=<px>&height=<px>[&fill=<hex>][&canvas=<name>]`
+    /// resizes the canvas, keeping its top left and filling new space with
+    /// `fill` (black by default)
+    async fn resize(&self, request: &Request) -> Result<String, String> {
+        let canvas = self.canvas(request)?;
+        let width: u32 = request
+            .parse_query_param("width")?
+            .ok_or("Missing width")?;
+        let height: u32 = request
+            .parse_query_param("height")?
+            .ok_or("Missing height")?;
+        // Binary mode addresses pixels with u16 coordinates
+        let max = u16::MAX as u32 + 1;
+        if width == 0 || height == 0 || width > max || height > max {
+            return Err("The canvas must be between 1x1 and 65536x65536".to_string());
+        }
+        let fill = match request.query_param("fill") {
+            Some(hex) => Color::from_hex(&hex).ok_or(format!("Invalid fill color '{}'", hex))?,
+            None => Color::black(),
+        };
+        let pixel_map = Arc::clone(&canvas.pixel_map);
+        tokio::task::spawn_blocking(move || pixel_map.resize(width, height, fill))
+            .await
+            .map_err(|e| format!("Resize failed: {}", e))?;
+        Ok(format!(
+            "Resized the canvas {} to {}x{}",
+            canvas.name, width, height
+        ))
+    }
+
+    /// `POST /admin/fill?color=<hex>[&canvas=<name>]` paints the whole canvas
+    /// in one color, `POST /admin/clear` paints it black
+    async fn fill(&self, request: &Request, color: Option<Color>) -> Result<String, String> {
+        let canvas = self.canvas(request)?;
+        let color = match color {
+            Some(color) => color,
+            None => {
+                let hex = request.query_param("color").ok_or("Missing color")?;
+                Color::from_hex(&hex).ok_or(format!("Invalid color '{}'", hex))?
+            }
+        };
+        let pixel_map = Arc::clone(&canvas.pixel_map);
+        tokio::task::spawn_blocking(move || pixel_map.fill(color))
+            .await
+            .map_err(|e| format!("Fill failed: {}", e))?;
+        Ok(format!("Filled the canvas {} with {}", canvas.name, color.hex()))
+    }
+
+    /// `POST /admin/load[?fit=<mode>][&canvas=<name>]` replaces the canvas
+    /// with the QOI, PNG or PPM image in the body. `fit` works like the
+    /// option of the same name.
+    async fn load(&self, request: &Request) -> Result<String, String> {
+        let canvas = self.canvas(request)?;
+        let fit: Fit = request.parse_query_param("fit")?.unwrap_or_default();
+        let data = request.body.clone();
+        let pixel_map = Arc::clone(&canvas.pixel_map);
+        let load = move || {
+            let image = image::decode(&data)?;
+            let image = fit.apply(image, pixel_map.get_size());
+            // Binary mode addresses pixels with u16 coordinates
+            let max = u16::MAX as u32 + 1;
+            if image.width == 0 || image.height == 0 || image.width > max || image.height > max {
+                return Err("The canvas must be between 1x1 and 65536x65536".to_string());
+            }
+            pixel_map.load(&image);
+            Ok((image.width, image.height))
+        };
+        let (width, height) = tokio::task::spawn_blocking(load)
+            .await
+            .map_err(|e| format!("Load failed: {}", e))??;
+        Ok(format!(
+            "Loaded a {}x{} image into the canvas {}",
+            width, height, canvas.name
+        ))
+    }
+
+    /// `POST /admin/snapshot[?canvas=<name>]` saves the canvas right away
+    async fn snapshot(&self, request: &Request) -> Result<String, String> {
+        let canvas = Arc::clone(self.canvas(request)?);
+        let path = canvas.snapshots.path().display().to_string();
+        let save = move || canvas.snapshots.save(&canvas.pixel_map);
+        match tokio::task::spawn_blocking(save).await {
+            Ok(Ok(true)) => Ok(format!("Saved snapshot to {}", path)),
+            Ok(Ok(false)) => Ok(format!("Snapshot {} is up to date", path)),
+            Ok(Err(e)) => Err(format!("Failed to save snapshot to {}: {}", path, e)),
+            Err(e) => Err(format!("Snapshot failed: {}", e)),
+        }
+    }
+
+    /// `GET /admin/connections` lists the open pixelflut connections as JSON,
+    /// with the hash their pixels are attributed to and the seconds they have
+    /// been connected for
+    fn list_connections(&self) -> Response {
+        let clients: Vec<String> = self
+            .connections
+            .list()
+            .iter()
+            .map(|client| {
+                format!(
+                    "{{\"id\":{},\"address\":\"{}\",\"ip_hash\":\"{:08x}\",\"connected\":{}}}",
+                    client.id,
+                    client.addr,
+                    client.ip_hash,
+                    client.connected.as_secs()
+                )
+            })
+            .collect();
+        Response::new(200).body("application/json", format!("[{}]", clients.join(",")))
+    }
+
+    /// `POST /admin/disconnect?id=<id>` closes one connection,
+    /// `POST /admin/disconnect?ip=<ip or range>` every connection from there
+    fn disconnect(&self, request: &Request) -> Result<String, String> {
+        if let Some(net) = request.parse_query_param::<IpNet>("ip")? {
+            let count = self.connections.disconnect_where(|ip| net.contains(ip));
+            return Ok(format!("Disconnected {} connections from {}", count, net));
+        }
+        let id: u64 = request.parse_query_param("id")?.ok_or("Missing id or ip")?;
+        match self.connections.disconnect(id) {
+            true => Ok(format!("Disconnected connection {}", id)),
+            false => Err(format!("No open connection {}", id)),
+        }
+    }
+
+    /// `POST /admin/ban?ip=<ip or range>` closes every connection from there
+    /// and rejects new ones. The ban is saved to the ban list file.
+    fn ban(&self, request: &Request) -> Result<String, String> {
+        let net: IpNet = request.parse_query_param("ip")?.ok_or("Missing ip")?;
+        if !self
+            .access
+            .ban(net)
+            .map_err(|e| format!("Failed to save the ban list: {}", e))?
+        {
+            return Err(format!("{} is already banned", net));
+        }
+        let count = self.connections.disconnect_where(|ip| net.contains(ip));
+        Ok(format!("Banned {}, closed {} connections", net, count))
+    }
+
+    /// `POST /admin/unban?ip=<ip or range>`, takes the entry as it was banned
+    fn unban(&self, request: &Request) -> Result<String, String> {
+        let net: IpNet = request.parse_query_param("ip")?.ok_or("Missing ip")?;
+        match self.access.unban(net) {
+            Ok(true) => Ok(format!("Unbanned {}", net)),
+            Ok(false) => Err(format!("{} is not banned", net)),
+            Err(e) => Err(format!("Failed to save the ban list: {}", e)),
+        }
+    }
+
+    /// `GET /admin/access` returns the ban list and, in allow list mode, the
+    /// allow list as JSON
+    fn list_access(&self) -> Response {
+        let json = |list: &[IpNet]| {
+            let entries: Vec<String> = list.iter().map(|net| format!("\"{}\"", net)).collect();
+            format!("[{}]", entries.join(","))
+        };
+        let (banned, allowed) = self.access.lists();
+        let allowed = allowed.map_or("null".to_string(), |allowed| json(&allowed));
+        Response::new(200).body(
+            "application/json",
+            format!("{{\"banned\":{},\"allowed\":{}}}", json(&banned), allowed),
+        )
+    }
+
+    /// `POST /admin/reload-access` reads the list files again right away and
+    /// closes the connections that aren't allowed anymore
+    async fn reload_access(&self) -> Result<String, String> {
+        let access = Arc::clone(&self.access);
+        tokio::task::spawn_blocking(move || access.reload(true))
+            .await
+            .map_err(|e| format!("Reload failed: {}", e))??;
+        let count = self
+            .connections
+            .disconnect_where(|ip| self.access.check(ip).is_err());
+        Ok(format!("Reloaded the access lists, closed {} connections", count))
+    }
+
+    /// `POST /admin/read-only?enabled=<true|false>[&canvas=<name>]` stops or
+    /// allows drawing, on every canvas if none is named
+    fn read_only(&self, request: &Request) -> Result<String, String> {
+        let enabled: bool = request
+            .parse_query_param("enabled")?
+            .ok_or("Missing enabled")?;
+        let state = match enabled {
+            true => "read-only",
+            false => "open for drawing",
+        };
+        if request.query_param("canvas").is_none() {
+            for canvas in self.canvases.iter() {
+                canvas.pixel_map.set_read_only(enabled);
+            }
+            return Ok(format!("Every canvas is {}", state));
+        }
+        let canvas = self.canvas(request)?;
+        canvas.pixel_map.set_read_only(enabled);
+        Ok(format!("The canvas {} is {}", canvas.name, state))
+    }
+}
+
+fn unauthorized() -> Response {
+    Response::new(401)
+        .header("WWW-Authenticate", "Bearer")
+        .text("Unauthorized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, HttpError};
+    use crate::pixel_map::PixelMap;
+    use crate::snapshot::Snapshots;
+
+    const TOKEN: &str = "0123456789abcdef0123";
+    /// Largest upload the tests allow
+    const MAX_UPLOAD: usize = 64;
+
+    fn admin() -> Admin {
+        let name = format!("pixelrust-admin-{}.qoi", std::process::id());
+        let snapshot = std::env::temp_dir().join(name);
+        let canvases = Canvases::new(NamedCanvas {
+            name: "main".to_string(),
+            pixel_map: Arc::new(PixelMap::new(4, 4)),
+            snapshots: Arc::new(Snapshots::new(snapshot, 0, 0)),
+        });
+        Admin::new(
+            TOKEN.to_string(),
+            Arc::new(canvases),
+            Arc::new(Connections::new(None, None)),
+            Arc::new(AccessList::open(None, None).unwrap()),
+            MAX_UPLOAD,
+        )
+    }
+
+    /// Parses a request with the admin token, or `authorization` instead
+    async fn request(head: &str, authorization: Option<&str>, body: &[u8]) -> Request {
+        let authorization = authorization.map_or(format!("Bearer {}", TOKEN), str::to_string);
+        let mut data = format!(
+            "{}\r\nHost: x\r\nAuthorization: {}\r\nContent-Length: {}\r\n\r\n",
+            head,
+            authorization,
+            body.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(body);
+        let mut stream = &data[..];
+        let mut buf = Vec::new();
+        let mut request = http::read_request(&mut stream, &mut buf).await.unwrap().unwrap();
+        http::read_body(&mut stream, &mut buf, &mut request, usize::MAX).await.unwrap();
+        request
+    }
+
+    /// Status line and body of the response
+    async fn send(admin: &Admin, head: &str, authorization: Option<&str>) -> (String, String) {
+        respond(admin, request(head, authorization, b"").await).await
+    }
+
+    async fn respond(admin: &Admin, request: Request) -> (String, String) {
+        let route = request.path.strip_prefix("/api").unwrap_or(&request.path).to_string();
+        let mut out = Vec::new();
+        let response = admin.respond(&request, &route).await;
+        response.write_to(&mut out, true, true).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.trim_end().to_string())
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_tokens() {
+        let admin = admin();
+        let wrong_byte = format!("Bearer {}4", &TOKEN[..TOKEN.len() - 1]);
+        let prefix = format!("Bearer {}", &TOKEN[..TOKEN.len() - 1]);
+        for authorization in ["", "Bearer", "Bearer ", &wrong_byte, &prefix, TOKEN] {
+            let head = "GET /api/admin/access HTTP/1.1";
+            let (status, _) = send(&admin, head, Some(authorization)).await;
+            assert_eq!(status, "HTTP/1.1 401 Unauthorized", "{:?}", authorization);
+            let request = request("POST /api/admin/load HTTP/1.1", Some(authorization), b"").await;
+            assert!(admin.max_body(&request).is_err());
+        }
+        // Unknown routes aren't revealed to strangers
+        let (status, _) = send(&admin, "GET /api/admin/nothing HTTP/1.1", Some("")).await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        let (status, _) = send(&admin, "GET /api/admin/access HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let request = request("POST /api/admin/load HTTP/1.1", None, b"").await;
+        assert_eq!(admin.max_body(&request).ok(), Some(MAX_UPLOAD));
+    }
+
+    #[tokio::test]
+    async fn routes_by_path_and_method() {
+        let admin = admin();
+        let (status, _) = send(&admin, "GET /api/admin/nothing HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = send(&admin, "GET /api/admin/clear HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (status, _) = send(&admin, "POST /api/admin/connections HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (status, body) = send(&admin, "GET /api/admin/connections HTTP/1.1", None).await;
+        assert_eq!((status.as_str(), body.as_str()), ("HTTP/1.1 200 OK", "[]"));
+    }
+
+    #[tokio::test]
+    async fn limits_uploads() {
+        let admin = admin();
+        let data = format!(
+            "POST /api/admin/load HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer {}\r\n\
+             Content-Length: {}\r\n\r\n",
+            TOKEN,
+            MAX_UPLOAD + 1
+        );
+        let mut stream = data.as_bytes();
+        let mut buf = Vec::new();
+        let mut request = http::read_request(&mut stream, &mut buf).await.unwrap().unwrap();
+        let max = admin.max_body(&request).ok().unwrap();
+        assert_eq!(
+            http::read_body(&mut stream, &mut buf, &mut request, max).await,
+            Err(HttpError::BodyTooLarge)
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_parameters() {
+        let admin = admin();
+        for head in [
+            "POST /api/admin/resize?height=2 HTTP/1.1",
+            "POST /api/admin/resize?width=0&height=2 HTTP/1.1",
+            "POST /api/admin/resize?width=65537&height=2 HTTP/1.1",
+            "POST /api/admin/resize?width=x&height=2 HTTP/1.1",
+            "POST /api/admin/resize?width=2&height=2&fill=nope HTTP/1.1",
+            "POST /api/admin/resize?width=2&height=2&canvas=other HTTP/1.1",
+            "POST /api/admin/fill HTTP/1.1",
+            "POST /api/admin/fill?color=red HTTP/1.1",
+            "POST /api/admin/load?fit=squash HTTP/1.1",
+            "POST /api/admin/disconnect HTTP/1.1",
+            "POST /api/admin/disconnect?id=7 HTTP/1.1",
+            "POST /api/admin/ban?ip=example.com HTTP/1.1",
+            "POST /api/admin/unban?ip=10.0.0.1 HTTP/1.1",
+            "POST /api/admin/read-only?enabled=maybe HTTP/1.1",
+        ] {
+            let (status, _) = send(&admin, head, None).await;
+            assert_eq!(status, "HTTP/1.1 400 Bad Request", "{}", head);
+        }
+        let (status, _) = send(&admin, "POST /api/admin/load HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        let empty = request("POST /api/admin/load HTTP/1.1", None, b"P6 0 5 255 ").await;
+        assert_eq!(respond(&admin, empty).await.0, "HTTP/1.1 400 Bad Request");
+
+        let head = "POST /api/admin/resize?width=3&height=2 HTTP/1.1";
+        let (status, _) = send(&admin, head, None).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(admin.canvases.main().pixel_map.get_size(), (3, 2));
+        let (status, _) = send(&admin, "POST /api/admin/ban?ip=10.0.0.0/8 HTTP/1.1", None).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let (_, body) = send(&admin, "GET /api/admin/access HTTP/1.1", None).await;
+        assert_eq!(body, r#"{"banned":["10.0.0.0/8"],"allowed":null}"#);
+    }
+}
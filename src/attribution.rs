@@ -0,0 +1,94 @@
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Who drew a pixel: the id of the pixelflut connection and a hash of its IP.
+/// The hash is keyed per server run, so it can't be turned back into the IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Writer {
+    pub connection: u32,
+    pub ip_hash: u32,
+}
+
+impl Writer {
+    fn pack(self) -> u64 {
+        (self.connection as u64) << 32 | self.ip_hash as u64
+    }
+
+    fn unpack(packed: u64) -> Writer {
+        Writer {
+            connection: (packed >> 32) as u32,
+            ip_hash: packed as u32,
+        }
+    }
+}
+
+/// The last write to a pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastWrite {
+    pub writer: Writer,
+    /// Unix seconds
+    pub time: u32,
+}
+
+/// Last writer and time of every pixel of a canvas, stored next to the pixels
+pub(crate) struct Attribution {
+    writers: Vec<AtomicU64>,
+    // 0 for pixels nobody drew yet
+    times: Vec<AtomicU32>,
+}
+
+impl Attribution {
+    pub fn new(len: usize) -> Attribution {
+        Attribution {
+            writers: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            times: (0..len).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, i: usize, writer: Writer, time: u32) {
+        self.writers[i].store(writer.pack(), Relaxed);
+        self.times[i].store(time, Relaxed);
+    }
+
+    pub fn get(&self, i: usize) -> Option<LastWrite> {
+        let time = self.times[i].load(Relaxed);
+        (time != 0).then(|| LastWrite {
+            writer: Writer::unpack(self.writers[i].load(Relaxed)),
+            time,
+        })
+    }
+}
+
+/// The current time as stored in the attribution
+pub fn now() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs.clamp(1, u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_last_write() {
+        let attribution = Attribution::new(2);
+        assert_eq!(attribution.get(0), None);
+        let writer = Writer {
+            connection: u32::MAX,
+            ip_hash: 0xdeadbeef,
+        };
+        attribution.record(1, writer, 1_700_000_000);
+        assert_eq!(
+            attribution.get(1),
+            Some(LastWrite {
+                writer,
+                time: 1_700_000_000
+            })
+        );
+        assert_eq!(attribution.get(0), None);
+    }
+}
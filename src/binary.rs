@@ -0,0 +1,54 @@
+use std::fmt::Write;
+
+use crate::attribution::Writer;
+use crate::color::Color;
+use crate::pixel_map::PixelMap;
+
+/// Size of one pixel in binary mode: [u16: x][u16: y][u32: rgba], little endian
+pub const RECORD_SIZE: usize = 8;
+
+/// How much is read from the socket at once in binary mode
+pub const CHUNK_SIZE: usize = RECORD_SIZE * 8192;
+
+/// Records [`apply_records`] left out
+#[derive(Debug, Default)]
+pub struct Skipped {
+    pub out_of_bounds: usize,
+    pub protected: usize,
+}
+
+/// Decodes the complete records in `records` and blends them onto the canvas
+/// in one batch. Records outside the canvas or in a protected region are
+/// skipped and counted. If `debug` is set, every pixel gets logged to it as a
+/// `PX` line.
+pub fn apply_records(
+    pixel_map: &PixelMap,
+    records: &[u8],
+    offset: (u32, u32),
+    writer: Writer,
+    mut debug: Option<&mut String>,
+) -> Skipped {
+    let (width, height) = pixel_map.get_size();
+    let mut skipped = Skipped::default();
+    let pixels = records.chunks_exact(RECORD_SIZE).filter_map(|record| {
+        let x = (u16::from_le_bytes([record[0], record[1]]) as u32).saturating_add(offset.0);
+        let y = (u16::from_le_bytes([record[2], record[3]]) as u32).saturating_add(offset.1);
+        if x >= width || y >= height {
+            skipped.out_of_bounds += 1;
+            return None;
+        }
+        if pixel_map.is_protected(x, y) {
+            skipped.protected += 1;
+            return None;
+        }
+        let color = Color::new(u32::from_le_bytes([
+            record[4], record[5], record[6], record[7],
+        ]));
+        if let Some(debug) = debug.as_mut() {
+            let _ = writeln!(debug, "PX {} {} {}", x, y, color.hex());
+        }
+        Some((x, y, color))
+    });
+    pixel_map.blend_pixels(pixels, writer);
+    skipped
+}
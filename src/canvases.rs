@@ -0,0 +1,189 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::pixel_map::PixelMap;
+use crate::snapshot::Snapshots;
+
+/// Name of the canvas set up by the top level options
+pub const MAIN_CANVAS: &str = "main";
+
+/// An additional canvas from the `canvases` option, written as
+/// `name[:WIDTHxHEIGHT][@LISTEN]`. Without a size it gets the size of the
+/// main canvas, `LISTEN` is a dedicated pixelflut address or just a port on
+/// the host of `--listen`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct CanvasSpec {
+    pub name: String,
+    pub size: Option<(u32, u32)>,
+    pub listen: Option<String>,
+}
+
+impl CanvasSpec {
+    /// The dedicated pixelflut address, if there is one
+    pub fn listen_address(&self, main_listen: &str) -> Option<String> {
+        let listen = self.listen.as_ref()?;
+        if !listen.bytes().all(|b| b.is_ascii_digit()) {
+            return Some(listen.clone());
+        }
+        let host = main_listen
+            .rsplit_once(':')
+            .map_or(main_listen, |(host, _)| host);
+        Some(format!("{}:{}", host, listen))
+    }
+}
+
+impl FromStr for CanvasSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (rest, listen) = match spec.split_once('@') {
+            Some((rest, listen)) if !listen.is_empty() => (rest, Some(listen.to_string())),
+            Some(_) => return Err(format!("Missing listen address in canvas '{}'", spec)),
+            None => (spec, None),
+        };
+        let (name, size) = match rest.split_once(':') {
+            Some((name, size)) => {
+                let size = size
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .ok_or_else(|| format!("Invalid size '{}' of canvas '{}'", size, name))?;
+                (name, Some(size))
+            }
+            None => (rest, None),
+        };
+        if !is_valid_name(name) {
+            return Err(format!(
+                "Invalid canvas name '{}' (use up to 32 of a-z, 0-9, - and _)",
+                name
+            ));
+        }
+        Ok(CanvasSpec {
+            name: name.to_string(),
+            size,
+            listen,
+        })
+    }
+}
+
+impl TryFrom<String> for CanvasSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        spec.parse()
+    }
+}
+
+/// Canvas names end up in URLs and the text protocol, so they are kept simple
+pub fn is_valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+}
+
+/// Where a canvas is saved: `image.qoi` becomes `image-<name>.qoi` for every
+/// canvas but the main one
+pub fn snapshot_path(main_snapshot: &str, name: &str) -> String {
+    if name == MAIN_CANVAS {
+        return main_snapshot.to_string();
+    }
+    let path = Path::new(main_snapshot);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, name),
+    };
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+/// One canvas of the server with everything that belongs to it
+pub(crate) struct NamedCanvas {
+    pub name: String,
+    pub pixel_map: Arc<PixelMap>,
+    pub snapshots: Arc<Snapshots>,
+}
+
+/// All canvases of the server, the main one first
+pub(crate) struct Canvases {
+    canvases: Vec<Arc<NamedCanvas>>,
+}
+
+impl Canvases {
+    pub fn new(main: NamedCanvas) -> Canvases {
+        Canvases {
+            canvases: vec![Arc::new(main)],
+        }
+    }
+
+    pub fn add(&mut self, canvas: NamedCanvas) {
+        self.canvases.push(Arc::new(canvas));
+    }
+
+    pub fn main(&self) -> &Arc<NamedCanvas> {
+        &self.canvases[0]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<NamedCanvas>> {
+        self.canvases.iter().find(|canvas| canvas.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<NamedCanvas>> {
+        self.canvases.iter()
+    }
+
+    /// The names separated by spaces, for error messages
+    pub fn names(&self) -> String {
+        let names: Vec<&str> = self.canvases.iter().map(|c| c.name.as_str()).collect();
+        names.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "sandbox".parse(),
+            Ok(CanvasSpec {
+                name: "sandbox".to_string(),
+                size: None,
+                listen: None
+            })
+        );
+        assert_eq!(
+            "workshop:320x240@1339".parse(),
+            Ok(CanvasSpec {
+                name: "workshop".to_string(),
+                size: Some((320, 240)),
+                listen: Some("1339".to_string())
+            })
+        );
+        assert!("Sandbox".parse::<CanvasSpec>().is_err());
+        assert!("a:10".parse::<CanvasSpec>().is_err());
+        assert!("a@".parse::<CanvasSpec>().is_err());
+        assert!("a.b".parse::<CanvasSpec>().is_err());
+    }
+
+    #[test]
+    fn derives_addresses_and_paths() {
+        let spec: CanvasSpec = "a@1339".parse().unwrap();
+        assert_eq!(
+            spec.listen_address("0.0.0.0:1337").as_deref(),
+            Some("0.0.0.0:1339")
+        );
+        let spec: CanvasSpec = "a@[::1]:1339".parse().unwrap();
+        assert_eq!(
+            spec.listen_address("0.0.0.0:1337").as_deref(),
+            Some("[::1]:1339")
+        );
+        assert_eq!(snapshot_path("data/image.qoi", "sandbox"), "data/image-sandbox.qoi");
+        assert_eq!(snapshot_path("image", "sandbox"), "image-sandbox");
+        assert_eq!(snapshot_path("image.qoi", MAIN_CANVAS), "image.qoi");
+    }
+}
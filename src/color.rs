@@ -1,5 +1,4 @@
 use std::fmt::Display;
-use std::num::ParseIntError;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Color {
@@ -37,14 +36,19 @@ impl Color {
         ((self.value) & 0xFF) as u8
     }
 
-    pub fn from_hex(hex: &str) -> Result<Self, ParseIntError> {
-        let mut raw = u32::from_str_radix(hex, 16)?;
-
-        if hex.len() == 6 {
-            raw = raw << 8 | 0xFF;
+    /// Parses `rrggbbaa`, `rrggbb` or the grayscale shorthand `ww`
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
         }
+        let raw = u32::from_str_radix(hex, 16).ok()?;
 
-        Ok(Color::new(raw))
+        match hex.len() {
+            2 => Some(Color::from_rgb(raw as u8, raw as u8, raw as u8)),
+            6 => Some(Color::new(raw << 8 | 0xFF)),
+            8 => Some(Color::new(raw)),
+            _ => None,
+        }
     }
 
     pub fn hex(&self) -> String {
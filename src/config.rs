@@ -0,0 +1,454 @@
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::canvases::{CanvasSpec, MAIN_CANVAS};
+use crate::image::Fit;
+use crate::overlay::RegionSpec;
+
+const USAGE: &str = "Usage: pixelrust [OPTIONS]
+       pixelrust export-timelapse <ARCHIVE> <DIR> [--format png|qoi]
+
+Options:
+  -c, --config <FILE>       Read settings from a TOML file
+      --listen <ADDR>       Pixelflut listen address (default: 0.0.0.0:1337)
+      --http-listen <ADDR>  HTTP/WebSocket listen address (default: localhost:1338)
+      --static-dir <DIR>    Serve the frontend from this directory on the HTTP
+                            server (default: off)
+      --admin-token <TOKEN> Enable the admin endpoints below /api/admin for
+                            requests with this bearer token (default: off)
+      --max-upload <MIB>    Largest image the admin endpoints accept (default: 8)
+      --width <PX>          Width of a fresh canvas (default: 1280)
+      --height <PX>         Height of a fresh canvas (default: 720)
+      --snapshot <FILE>     Canvas snapshot file (default: image.qoi)
+      --canvases <LIST>     Additional canvases next to the main one, separated
+                            by commas: name[:WIDTHxHEIGHT][@PORT or ADDR].
+                            Each is saved next to the snapshot as
+                            <snapshot>-<name>.qoi (default: none)
+      --protect <LIST>      Regions clients can't draw on, separated by commas:
+                            [canvas:]WIDTHxHEIGHT+X+Y[=IMAGE]. The image is
+                            stretched over the region (default: none)
+      --import <FILE>       Image to start from if there is no snapshot yet
+                            (QOI, PNG or PPM, default: off)
+      --fit <MODE>          How an imported image of another size is fitted
+                            into the canvas: keep (use the image size),
+                            stretch or letterbox (default: keep)
+      --snapshot-interval <SECS>
+                            Seconds between two snapshots (default: 60)
+      --snapshot-keep <N>   Number of older snapshots to keep (default: 5)
+      --timelapse <FILE>    Record a timelapse into this archive (default: off)
+      --timelapse-interval <SECS>
+                            Seconds between two timelapse frames (default: 10)
+      --history <DIR>       Keep a rolling history of the canvas for replays in
+                            this directory (default: off)
+      --history-interval <SECS>
+                            Seconds between two history frames (default: 5)
+      --history-retention <HOURS>
+                            Hours of history to keep (default: 24)
+      --workers <N>         Number of runtime worker threads (default: one per core)
+      --tick-rate <FPS>     Frames per second sent to viewers, at most 1000
+                            (default: 30)
+      --pixel-rate <N>      Pixels per second a single connection may set (default: unlimited)
+      --ip-pixel-rate <N>   Pixels per second all connections of one IP may set (default: unlimited)
+      --total-pixel-rate <N>
+                            Pixels per second for all clients together, split
+                            evenly between them (default: unlimited)
+      --max-connections <N> Maximum number of pixelflut connections (default: unlimited)
+      --max-connections-per-ip <N>
+                            Maximum number of pixelflut connections from one IP
+                            (default: unlimited)
+      --ban-list <FILE>     IPs and CIDR ranges that may not connect, one per
+                            line. Admin bans are added to it (default: off)
+      --allow-list <FILE>   Only let IPs and CIDR ranges from this file connect
+                            (default: off)
+      --attribution <BOOL>  Remember which connection last drew each pixel, for
+                            WHO and /api/who (default: false)
+  -h, --help                Print this help
+
+Every option can also be set with an environment variable named
+PIXELRUST_<OPTION> (e.g. PIXELRUST_HTTP_LISTEN). Command line flags take
+precedence over environment variables, which take precedence over the
+config file.
+";
+
+/// Highest frame rate sent to viewers, faster ticks only burn CPU
+const MAX_TICK_RATE: u32 = 1000;
+
+/// Names of all options, as used for the flags
+const OPTIONS: &[&str] = &[
+    "listen",
+    "http-listen",
+    "static-dir",
+    "admin-token",
+    "max-upload",
+    "width",
+    "height",
+    "snapshot",
+    "canvases",
+    "protect",
+    "import",
+    "fit",
+    "snapshot-interval",
+    "snapshot-keep",
+    "timelapse",
+    "timelapse-interval",
+    "history",
+    "history-interval",
+    "history-retention",
+    "workers",
+    "tick-rate",
+    "pixel-rate",
+    "ip-pixel-rate",
+    "total-pixel-rate",
+    "max-connections",
+    "max-connections-per-ip",
+    "ban-list",
+    "allow-list",
+    "attribution",
+];
+
+/// Server settings. Loaded from (in increasing priority) the built-in
+/// defaults, an optional TOML file, `PIXELRUST_*` environment variables
+/// and command line flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub listen: String,
+    pub http_listen: String,
+    pub static_dir: Option<String>,
+    pub admin_token: Option<String>,
+    pub max_upload: usize,
+    pub width: u32,
+    pub height: u32,
+    pub snapshot: String,
+    pub canvases: Vec<CanvasSpec>,
+    pub protect: Vec<RegionSpec>,
+    pub import: Option<String>,
+    pub fit: Fit,
+    pub snapshot_interval: u64,
+    pub snapshot_keep: usize,
+    pub timelapse: Option<String>,
+    pub timelapse_interval: u64,
+    pub history: Option<String>,
+    pub history_interval: u64,
+    pub history_retention: u64,
+    pub workers: Option<usize>,
+    pub tick_rate: u32,
+    pub pixel_rate: Option<u32>,
+    pub ip_pixel_rate: Option<u32>,
+    pub total_pixel_rate: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub ban_list: Option<String>,
+    pub allow_list: Option<String>,
+    pub attribution: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "0.0.0.0:1337".to_string(),
+            http_listen: "localhost:1338".to_string(),
+            static_dir: None,
+            admin_token: None,
+            max_upload: 8,
+            width: 1280,
+            height: 720,
+            snapshot: "image.qoi".to_string(),
+            canvases: Vec::new(),
+            protect: Vec::new(),
+            import: None,
+            fit: Fit::Keep,
+            snapshot_interval: 60,
+            snapshot_keep: 5,
+            timelapse: None,
+            timelapse_interval: 10,
+            history: None,
+            history_interval: 5,
+            history_retention: 24,
+            workers: None,
+            tick_rate: 30,
+            pixel_rate: None,
+            ip_pixel_rate: None,
+            total_pixel_rate: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            ban_list: None,
+            allow_list: None,
+            attribution: false,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the config from the process arguments and environment.
+    /// Prints the usage and exits on `--help`.
+    pub fn load() -> Result<Config, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        Config::build(&args, |var| env::var(var).ok())
+    }
+
+    /// Builds the config from `args` and the environment variables that `var`
+    /// looks up
+    fn build(args: &[String], var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let config_file = find_config_flag(args)?.or_else(|| var("PIXELRUST_CONFIG"));
+
+        let mut config = match config_file {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env(var)?;
+        config.apply_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("Invalid config file {}: {}", path, e))
+    }
+
+    fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        for key in OPTIONS {
+            let var = format!("PIXELRUST_{}", key.replace('-', "_").to_uppercase());
+            if let Some(value) = lookup(&var) {
+                self.set(key, &value)
+                    .map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (key, inline_value) = match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let key = match key {
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-c" => "config",
+                key if key.starts_with("--") => &key[2..],
+                _ => return Err(format!("Unexpected argument '{}'\n\n{}", arg, USAGE)),
+            };
+            let value = match inline_value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("Missing value for --{}", key))?,
+            };
+            if key == "config" {
+                continue;
+            }
+            self.set(key, &value)
+                .map_err(|e| format!("--{}: {}\n\n{}", key, e, USAGE))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => self.listen = value.to_string(),
+            "http-listen" => self.http_listen = value.to_string(),
+            "static-dir" => self.static_dir = Some(value.to_string()),
+            "admin-token" => self.admin_token = Some(value.to_string()),
+            "max-upload" => self.max_upload = parse(value)?,
+            "width" => self.width = parse(value)?,
+            "height" => self.height = parse(value)?,
+            "snapshot" => self.snapshot = value.to_string(),
+            "canvases" => self.canvases = parse_list(value)?,
+            "protect" => self.protect = parse_list(value)?,
+            "import" => self.import = Some(value.to_string()),
+            "fit" => self.fit = parse(value)?,
+            "snapshot-interval" => self.snapshot_interval = parse(value)?,
+            "snapshot-keep" => self.snapshot_keep = parse(value)?,
+            "timelapse" => self.timelapse = Some(value.to_string()),
+            "timelapse-interval" => self.timelapse_interval = parse(value)?,
+            "history" => self.history = Some(value.to_string()),
+            "history-interval" => self.history_interval = parse(value)?,
+            "history-retention" => self.history_retention = parse(value)?,
+            "workers" => self.workers = Some(parse(value)?),
+            "tick-rate" => self.tick_rate = parse(value)?,
+            "pixel-rate" => self.pixel_rate = Some(parse(value)?),
+            "ip-pixel-rate" => self.ip_pixel_rate = Some(parse(value)?),
+            "total-pixel-rate" => self.total_pixel_rate = Some(parse(value)?),
+            "max-connections" => self.max_connections = Some(parse(value)?),
+            "max-connections-per-ip" => self.max_connections_per_ip = Some(parse(value)?),
+            "ban-list" => self.ban_list = Some(value.to_string()),
+            "allow-list" => self.allow_list = Some(value.to_string()),
+            "attribution" => self.attribution = parse(value)?,
+            _ => return Err(format!("Unknown option '{}'", key)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("Canvas width and height must be greater than 0".to_string());
+        }
+        // Binary mode addresses pixels with u16 coordinates
+        if self.width > u16::MAX as u32 + 1 || self.height > u16::MAX as u32 + 1 {
+            return Err("Canvas width and height must not exceed 65536".to_string());
+        }
+        for (i, canvas) in self.canvases.iter().enumerate() {
+            let taken = self.canvases[..i].iter().any(|c| c.name == canvas.name);
+            if canvas.name == MAIN_CANVAS || taken {
+                return Err(format!("Canvas name '{}' is used twice", canvas.name));
+            }
+            let (width, height) = canvas.size.unwrap_or((self.width, self.height));
+            let max = u16::MAX as u32 + 1;
+            if width == 0 || height == 0 || width > max || height > max {
+                return Err(format!(
+                    "Canvas '{}' must be between 1x1 and 65536x65536",
+                    canvas.name
+                ));
+            }
+        }
+        for region in &self.protect {
+            let known = region.canvas == MAIN_CANVAS
+                || self.canvases.iter().any(|canvas| canvas.name == region.canvas);
+            if !known {
+                return Err(format!("Protected region on unknown canvas '{}'", region.canvas));
+            }
+        }
+        if self.admin_token.as_ref().is_some_and(|token| token.len() < 16) {
+            return Err("The admin token must be at least 16 characters long".to_string());
+        }
+        if self.max_upload == 0 || self.max_upload > 1024 {
+            return Err("The upload limit must be between 1 and 1024 MiB".to_string());
+        }
+        if self.workers == Some(0) {
+            return Err("Worker thread count must be greater than 0".to_string());
+        }
+        if self.snapshot_interval == 0 {
+            return Err("Snapshot interval must be greater than 0".to_string());
+        }
+        if self.timelapse_interval == 0 {
+            return Err("Timelapse interval must be greater than 0".to_string());
+        }
+        if self.history_interval == 0 || self.history_retention == 0 {
+            return Err("History interval and retention must be greater than 0".to_string());
+        }
+        // Used in milliseconds
+        if self.history_retention.checked_mul(60 * 60 * 1000).is_none() {
+            return Err("History retention is too long".to_string());
+        }
+        if self.tick_rate == 0 || self.tick_rate > MAX_TICK_RATE {
+            return Err(format!("Tick rate must be between 1 and {}", MAX_TICK_RATE));
+        }
+        if [self.pixel_rate, self.ip_pixel_rate, self.total_pixel_rate].contains(&Some(0)) {
+            return Err("Pixel rates must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The config file has to be known before anything else is applied, so it
+/// is looked up separately from the other flags.
+fn find_config_flag(args: &[String]) -> Result<Option<String>, String> {
+    let mut config = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(path) = arg.strip_prefix("--config=") {
+            config = Some(path.to_string());
+        } else if arg == "-c" || arg == "--config" {
+            config = Some(
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| "Missing value for --config".to_string())?,
+            );
+        }
+    }
+    Ok(config)
+}
+
+/// Parses a comma separated list, skipping empty entries
+fn parse_list<T: FromStr<Err = String>>(value: &str) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value '{}': {}", value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn build(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        Config::build(&args, |var| vars.get(var).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn flags_override_env_overrides_file() {
+        let name = format!("pixelrust-config-{}.toml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let toml = "width = 10\nheight = 20\ntick_rate = 5\nlisten = \"file\"\n";
+        std::fs::write(&path, toml).unwrap();
+        let path = path.to_str().unwrap();
+        let vars = [
+            ("PIXELRUST_HEIGHT", "30"),
+            ("PIXELRUST_TICK_RATE", "6"),
+            ("PIXELRUST_CONFIG", path),
+        ];
+
+        let config = build(&["--tick-rate", "7"], &vars).unwrap();
+        assert_eq!(config.listen, "file");
+        assert_eq!((config.width, config.height, config.tick_rate), (10, 30, 7));
+        // Untouched options keep their defaults
+        assert_eq!(config.snapshot, "image.qoi");
+
+        let config = build(&["-c", path, "--height=40"], &[]).unwrap();
+        assert_eq!((config.width, config.height, config.tick_rate), (10, 40, 5));
+        std::fs::remove_file(path).unwrap();
+
+        let config = build(&[], &[]).unwrap();
+        assert_eq!((config.width, config.height, config.tick_rate), (1280, 720, 30));
+        assert!(build(&["--config", "/nonexistent/pixelrust.toml"], &[]).is_err());
+        assert!(build(&["--nope", "1"], &[]).is_err());
+        assert!(build(&["--width"], &[]).is_err());
+        assert!(build(&[], &[("PIXELRUST_WIDTH", "wide")]).is_err());
+    }
+
+    #[test]
+    fn validates_values() {
+        for args in [
+            &["--width", "0"][..],
+            &["--height", "65537"],
+            &["--tick-rate", "0"],
+            &["--tick-rate", "1001"],
+            &["--admin-token", "too-short"],
+            &["--max-upload", "0"],
+            &["--history-retention", "18446744073709551615"],
+            &["--pixel-rate", "0"],
+            &["--canvases", "sandbox,sandbox"],
+            &["--canvases", "main"],
+            &["--protect", "nowhere:2x2+0+0"],
+        ] {
+            assert!(build(args, &[]).is_err(), "{:?}", args);
+        }
+        let config = build(&["--tick-rate", "1000", "--admin-token", "0123456789abcdef"], &[]);
+        assert_eq!(config.unwrap().tick_rate, 1000);
+    }
+}
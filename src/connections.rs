@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::attribution::Writer;
+use crate::metrics::{GaugeGuard, METRICS};
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Keeps track of the open pixelflut connections and enforces the limits on them
+pub(crate) struct Connections {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    open: Mutex<OpenConnections>,
+    // Random key of the IP hashes, so they can't be reversed
+    ip_hasher: RandomState,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    clients: HashMap<u64, Client>,
+    next_id: u64,
+}
+
+struct Client {
+    addr: SocketAddr,
+    since: Instant,
+    kick: Arc<Notify>,
+}
+
+/// An open connection, as listed to admins
+pub(crate) struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub ip_hash: u32,
+    pub connected: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+    Banned,
+    NotAllowed,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::TooManyConnections => write!(f, "ERR: Too Many Connections"),
+            Rejection::TooManyConnectionsFromIp => {
+                write!(f, "ERR: Too Many Connections From Your IP")
+            }
+            Rejection::Banned => write!(f, "ERR: Banned"),
+            Rejection::NotAllowed => write!(f, "ERR: Not Allowed"),
+        }
+    }
+}
+
+impl Connections {
+    pub fn new(max_total: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        Connections {
+            max_total,
+            max_per_ip,
+            open: Mutex::new(OpenConnections::default()),
+            ip_hasher: RandomState::new(),
+        }
+    }
+
+    /// A short hash of `ip` that stays the same while the server runs
+    pub fn ip_hash(&self, ip: IpAddr) -> u32 {
+        self.ip_hasher.hash_one(ip.to_canonical()) as u32
+    }
+
+    /// Counts a new connection from `addr` if the limits allow it. The
+    /// connection is counted until the returned guard is dropped.
+    pub fn open(self: &Arc<Self>, addr: SocketAddr) -> Result<ConnectionGuard, Rejection> {
+        let ip = addr.ip();
+        let mut open = self.open.lock().unwrap();
+        if self.max_total.is_some_and(|max| open.total >= max) {
+            return Err(Rejection::TooManyConnections);
+        }
+        let from_ip = open.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(Rejection::TooManyConnectionsFromIp);
+        }
+        open.total += 1;
+        open.per_ip.insert(ip, from_ip + 1);
+        let id = open.next_id;
+        open.next_id += 1;
+        let kick = Arc::new(Notify::new());
+        let client = Client {
+            addr,
+            since: Instant::now(),
+            kick: Arc::clone(&kick),
+        };
+        open.clients.insert(id, client);
+        Ok(ConnectionGuard {
+            connections: Arc::clone(self),
+            ip,
+            id,
+            kick,
+            _gauge: GaugeGuard::new(&METRICS.tcp_connections),
+        })
+    }
+
+    /// The open connections, oldest first
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let open = self.open.lock().unwrap();
+        let mut clients: Vec<ClientInfo> = open
+            .clients
+            .iter()
+            .map(|(&id, client)| ClientInfo {
+                id,
+                addr: client.addr,
+                ip_hash: self.ip_hash(client.addr.ip()),
+                connected: client.since.elapsed(),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// Closes the connection with the given id. Returns whether it was open.
+    pub fn disconnect(&self, id: u64) -> bool {
+        let open = self.open.lock().unwrap();
+        match open.clients.get(&id) {
+            Some(client) => {
+                client.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes every connection from an IP that `close` picks and returns how
+    /// many there were
+    pub fn disconnect_where(&self, close: impl Fn(IpAddr) -> bool) -> usize {
+        let open = self.open.lock().unwrap();
+        let mut closed = 0;
+        for client in open.clients.values() {
+            if close(client.addr.ip()) {
+                client.kick.notify_one();
+                closed += 1;
+            }
+        }
+        closed
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+    id: u64,
+    kick: Arc<Notify>,
+    _gauge: GaugeGuard,
+}
+
+impl ConnectionGuard {
+    /// Completes once an admin disconnects the client
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    /// Who the pixels drawn over this connection are attributed to. Ids past
+    /// u32::MAX all show up as u32::MAX.
+    pub fn writer(&self) -> Writer {
+        Writer {
+            connection: u32::try_from(self.id).unwrap_or(u32::MAX),
+            ip_hash: self.connections.ip_hash(self.ip),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.clients.remove(&self.id);
+        open.total -= 1;
+        if let Some(count) = open.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Tells the client why it got rejected and closes the connection
+pub async fn reject(mut socket: TcpStream, rejection: Rejection) {
+    let _ = socket
+        .write_all(format!("{}\n", rejection).as_bytes())
+        .await;
+    let _ = socket.shutdown().await;
+}
+
+/// Accepts the next connection. Errors like running out of file descriptors
+/// are logged and retried with an increasing delay instead of giving up.
+pub async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        match listener.accept().await {
+            Ok(connection) => return connection,
+            Err(e) => {
+                println!(
+                    "Failed to accept connection, retrying in {:?}: {}",
+                    backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((ip, port))
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let connections = Arc::new(Connections::new(Some(3), Some(2)));
+        let first = connections.open(addr([10, 0, 0, 1], 1)).unwrap();
+        let _second = connections.open(addr([10, 0, 0, 1], 2)).unwrap();
+        assert_eq!(
+            connections.open(addr([10, 0, 0, 1], 3)).err(),
+            Some(Rejection::TooManyConnectionsFromIp)
+        );
+        let _third = connections.open(addr([10, 0, 0, 2], 1)).unwrap();
+        assert_eq!(
+            connections.open(addr([10, 0, 0, 3], 1)).err(),
+            Some(Rejection::TooManyConnections)
+        );
+        // Closing a connection frees its slot
+        drop(first);
+        let _fourth = connections.open(addr([10, 0, 0, 1], 4)).unwrap();
+        assert_eq!(connections.list().len(), 3);
+    }
+
+    /// Whether the guard is kicked right away
+    async fn kicked(guard: &ConnectionGuard) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), guard.kicked())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn kicks_the_picked_connections() {
+        let connections = Arc::new(Connections::new(None, None));
+        let a = connections.open(addr([10, 0, 0, 1], 1)).unwrap();
+        let b = connections.open(addr([10, 0, 0, 1], 2)).unwrap();
+        let c = connections.open(addr([10, 0, 0, 2], 1)).unwrap();
+
+        assert!(connections.disconnect(c.id));
+        assert!(kicked(&c).await);
+        assert!(!kicked(&a).await);
+        drop(c);
+        assert!(!connections.disconnect(2));
+
+        let ip = addr([10, 0, 0, 1], 0).ip();
+        assert_eq!(connections.disconnect_where(|other| other == ip), 2);
+        assert!(kicked(&a).await);
+        assert!(kicked(&b).await);
+    }
+
+    #[test]
+    fn writer_ids_saturate() {
+        let connections = Arc::new(Connections::new(None, None));
+        let mut guard = connections.open(addr([10, 0, 0, 1], 1)).unwrap();
+        assert_eq!(guard.writer().connection, 0);
+        guard.id = u64::from(u32::MAX) + 1;
+        assert_eq!(guard.writer().connection, u32::MAX);
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rapid_qoi::{Colors, Qoi};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+use crate::pixel_map::{self, PixelMap, TILE_SIZE};
+use crate::timelapse::{now_ms, Frame, Frames, Recorder};
+
+/// Time covered by a single segment file
+const SEGMENT_LENGTH: Duration = Duration::from_secs(60 * 60);
+const SEGMENT_EXTENSION: &str = "pxtl";
+
+/// Rolling history of the canvas. Frames are recorded into timelapse archives
+/// in `dir`, one per SEGMENT_LENGTH, named after the unix time in ms at which
+/// they were started. Segments that are completely older than `retention` are
+/// deleted.
+pub(crate) struct History {
+    dir: PathBuf,
+    retention: Duration,
+    // Start time and recorder of the segment that is currently written to
+    segment: Mutex<Option<(u64, Recorder)>>,
+}
+
+/// An update of a replay, ready to be sent to a viewer
+pub(crate) struct ReplayFrame {
+    pub time_ms: u64,
+    // Deflated QOI image or delta frame, like the ones of the live view
+    pub data: Vec<u8>,
+}
+
+impl History {
+    pub fn open(dir: impl Into<PathBuf>, retention: Duration) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(History {
+            dir,
+            retention,
+            segment: Mutex::new(None),
+        })
+    }
+
+    /// Records the canvas if it changed since the last frame, starting a new
+    /// segment when the current one is full
+    pub fn record(&self, pixel_map: &PixelMap) -> io::Result<bool> {
+        let now = now_ms();
+        let mut segment = self.segment.lock().unwrap();
+        let full = match &*segment {
+            Some((start, _)) => now.saturating_sub(*start) >= SEGMENT_LENGTH.as_millis() as u64,
+            None => true,
+        };
+        if full {
+            let path = self.dir.join(format!("{:013}.{}", now, SEGMENT_EXTENSION));
+            *segment = Some((now, Recorder::open(path)?));
+            self.prune(now)?;
+        }
+        segment.as_ref().unwrap().1.record(pixel_map)
+    }
+
+    /// Records a frame every `interval`, until the runtime shuts down
+    pub async fn run(self: Arc<Self>, pixel_map: Arc<PixelMap>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let history = Arc::clone(&self);
+            let pixel_map = Arc::clone(&pixel_map);
+            let result = tokio::task::spawn_blocking(move || history.record(&pixel_map)).await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to record history in {}: {}", self.dir.display(), e),
+                Err(e) => println!("History task failed: {}", e),
+            }
+        }
+    }
+
+    /// Deletes the segments that only hold frames older than the retention
+    fn prune(&self, now: u64) -> io::Result<()> {
+        let cutoff = now.saturating_sub(self.retention.as_millis() as u64);
+        let segments = self.segments()?;
+        // A segment ends where the next one starts
+        for pair in segments.windows(2) {
+            if pair[1].0 <= cutoff {
+                fs::remove_file(&pair[0].1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All segments with their start time, oldest first
+    fn segments(&self) -> io::Result<Vec<(u64, PathBuf)>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let start = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            if let Some(start) = start {
+                segments.push((start, path));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Time range covered by the history in unix ms, None if it is empty
+    pub fn range(&self) -> io::Result<Option<(u64, u64)>> {
+        Ok(self
+            .segments()?
+            .first()
+            .map(|&(start, _)| (start, now_ms().max(start))))
+    }
+
+    /// Decodes the history from `start_ms` on and sends it into `updates`.
+    /// The first update is the full canvas at `start_ms`, every later one only
+    /// holds the tiles that changed. Blocks, so run it on a blocking thread.
+    /// Stops at the end of the history or when the receiver is dropped.
+    pub fn replay(&self, start_ms: u64, updates: mpsc::Sender<ReplayFrame>) {
+        let segments = match self.segments() {
+            Ok(segments) => segments,
+            Err(e) => {
+                println!("Failed to read history in {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        let first = segments
+            .iter()
+            .rposition(|&(start, _)| start <= start_ms)
+            .unwrap_or(0);
+        // Last frame before the start, it is what the canvas looked like then
+        let mut base: Option<Frame> = None;
+        let mut sent: Option<Frame> = None;
+        for (_, path) in &segments[first..] {
+            let frames = match Frames::open(path) {
+                Ok(frames) => frames,
+                Err(e) => {
+                    println!("Skipping history segment {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            // An error is either damage or the end of the segment that is
+            // still being written, either way there is nothing more to read
+            for frame in frames.map_while(Result::ok) {
+                if sent.is_none() && frame.time_ms <= start_ms {
+                    base = Some(frame);
+                    continue;
+                }
+                for frame in base.take().into_iter().chain([frame]) {
+                    let update = ReplayFrame {
+                        time_ms: frame.time_ms,
+                        data: encode_update(sent.as_ref(), &frame),
+                    };
+                    if updates.blocking_send(update).is_err() {
+                        return;
+                    }
+                    sent = Some(frame);
+                }
+            }
+        }
+        // The start is after the last frame, show the last state
+        if let Some(frame) = base {
+            let _ = updates.blocking_send(ReplayFrame {
+                time_ms: frame.time_ms,
+                data: encode_update(None, &frame),
+            });
+        }
+    }
+}
+
+/// Deflated delta frame from `previous` to `frame`, or the whole frame as
+/// QOI if there is no usable previous frame or most of the tiles changed
+fn encode_update(previous: Option<&Frame>, frame: &Frame) -> Vec<u8> {
+    let (width, height) = (frame.width, frame.height);
+    let tiles = match previous {
+        Some(previous) if (previous.width, previous.height) == (width, height) => {
+            changed_tiles(width, height, &previous.rgba, &frame.rgba)
+        }
+        _ => None,
+    };
+    let update = match tiles {
+        Some(tiles) => pixel_map::encode_tiles(width, height, &tiles, |x, y, buf| {
+            let i = (x as usize + y as usize * width as usize) * 4;
+            buf.extend_from_slice(&frame.rgba[i..i + 4]);
+        }),
+        None => Qoi {
+            width,
+            height,
+            colors: Colors::Rgba,
+        }
+        .encode_alloc(&frame.rgba)
+        .unwrap(),
+    };
+    fdeflate::compress_to_vec(&update)
+}
+
+/// Tiles that differ between two frames of the same size, None if more than
+/// half of them did, as a full frame is cheaper then
+fn changed_tiles(width: u32, height: u32, a: &[u8], b: &[u8]) -> Option<Vec<u32>> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+    let changed: Vec<u32> = (0..tiles_x * tiles_y)
+        .filter(|tile| {
+            let x = (tile % tiles_x) * TILE_SIZE;
+            let y = (tile / tiles_x) * TILE_SIZE;
+            let w = TILE_SIZE.min(width - x);
+            (y..(y + TILE_SIZE).min(height)).any(|row| {
+                let start = (x as usize + row as usize * width as usize) * 4;
+                let end = start + w as usize * 4;
+                a[start..end] != b[start..end]
+            })
+        })
+        .collect();
+    (changed.len() * 2 <= (tiles_x * tiles_y) as usize).then_some(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribution::Writer;
+    use crate::color::Color;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pixelrust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn finds_changed_tiles() {
+        let (width, height) = (TILE_SIZE * 2, TILE_SIZE + 1);
+        let a = vec![0u8; (width * height * 4) as usize];
+        let mut b = a.clone();
+        assert_eq!(changed_tiles(width, height, &a, &b), Some(vec![]));
+        // The last pixel, in the cut off bottom right tile
+        let last = b.len() - 1;
+        b[last] = 1;
+        assert_eq!(changed_tiles(width, height, &a, &b), Some(vec![3]));
+        b[0] = 1;
+        assert_eq!(changed_tiles(width, height, &a, &b), Some(vec![0, 3]));
+        b[(TILE_SIZE * 4) as usize] = 1;
+        assert_eq!(changed_tiles(width, height, &a, &b), None);
+    }
+
+    #[test]
+    fn prunes_segments_past_the_retention() {
+        let dir = temp_dir("history-prune");
+        let history = History::open(&dir, Duration::from_secs(10)).unwrap();
+        for start in [1_000, 5_000, 12_000, 30_000] {
+            fs::write(dir.join(format!("{:013}.{}", start, SEGMENT_EXTENSION)), b"").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+        history.prune(20_000).unwrap();
+        // 5000 ends at 12000 but still holds frames from after the cutoff
+        let starts: Vec<u64> = history.segments().unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(starts, [5_000, 12_000, 30_000]);
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_a_full_frame_then_deltas() {
+        let dir = temp_dir("history-replay");
+        let history = History::open(&dir, Duration::from_secs(60)).unwrap();
+        let pixel_map = PixelMap::new(TILE_SIZE * 4, TILE_SIZE);
+        history.record(&pixel_map).unwrap();
+        for x in [0, TILE_SIZE * 3] {
+            pixel_map.blend_color(x, 0, Color::from_rgb(255, 0, 0), Writer::default());
+            history.record(&pixel_map).unwrap();
+        }
+
+        let replay = |start_ms| {
+            let (sender, mut receiver) = mpsc::channel(8);
+            history.replay(start_ms, sender);
+            let mut updates = Vec::new();
+            while let Ok(update) = receiver.try_recv() {
+                updates.push(fdeflate::decompress_to_vec(&update.data).unwrap());
+            }
+            updates
+        };
+        let updates = replay(0);
+        assert_eq!(updates.len(), 3);
+        let (_, first) = Qoi::decode_alloc(&updates[0]).unwrap();
+        assert!(first.iter().all(|&b| b == 0 || b == 255));
+        // Only the tile that changed
+        assert_eq!(updates[2][0], b'D');
+        assert_eq!(u32::from_le_bytes(updates[2][1..5].try_into().unwrap()), 1);
+
+        // After the end, the last state as a whole
+        let updates = replay(u64::MAX);
+        assert_eq!(updates.len(), 1);
+        let (_, last) = Qoi::decode_alloc(&updates[0]).unwrap();
+        assert_eq!(last, pixel_map.to_image().rgba);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest request line plus headers that is accepted
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// A parsed HTTP/1.x request
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    query: Option<String>,
+    /// Minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1
+    pub version: u8,
+    /// Header names are lowercase, repeated headers are joined with ", "
+    headers: HashMap<String, String>,
+    content_length: usize,
+    /// Empty until read with [`read_body`]
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HttpError {
+    /// The request is malformed, answered with 400
+    BadRequest(&'static str),
+    /// The request line and headers are too large, answered with 431
+    HeadTooLarge,
+    /// The body is too large, answered with 413
+    BodyTooLarge,
+    /// The connection was closed in the middle of a request
+    UnexpectedEof,
+    Io(std::io::ErrorKind),
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HttpError::BadRequest(reason) => write!(f, "Bad request: {}", reason),
+            HttpError::HeadTooLarge => write!(f, "Request headers too large"),
+            HttpError::BodyTooLarge => write!(f, "Request body too large"),
+            HttpError::UnexpectedEof => write!(f, "Connection closed in the middle of a request"),
+            HttpError::Io(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+impl HttpError {
+    /// Response to send for this error, if the connection is still usable
+    pub fn response(&self) -> Option<Response> {
+        let status = match self {
+            HttpError::BadRequest(_) => 400,
+            HttpError::HeadTooLarge => 431,
+            HttpError::BodyTooLarge => 413,
+            HttpError::UnexpectedEof | HttpError::Io(_) => return None,
+        };
+        Some(Response::new(status).text(&self.to_string()))
+    }
+}
+
+impl Request {
+    /// Value of the header `name`, which has to be lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Percent decoded value of `name` in the query string
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .find(|(key, _)| percent_decode(key) == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+
+    /// Parses the query parameter `name`, None if it isn't given
+    pub fn parse_query_param<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.query_param(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid value '{}' for {}", value, name))
+            })
+            .transpose()
+    }
+
+    /// Size of the body announced in the headers
+    pub fn content_length(&self) -> usize {
+        self.content_length
+    }
+
+    /// Whether the connection stays open after the response
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or("").to_ascii_lowercase();
+        let has = |token: &str| connection.split(',').any(|t| t.trim() == token);
+        match self.version {
+            0 => has("keep-alive"),
+            _ => !has("close"),
+        }
+    }
+
+    /// Whether this is a WebSocket handshake
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Reads the request line and headers of the next request from `stream`.
+/// `buf` holds bytes that were read but not used yet and has to be passed to
+/// every call on the same connection, so pipelined requests aren't lost.
+/// Returns None if the connection was closed before a new request started.
+/// The body has to be read with [`read_body`] before the next request.
+pub async fn read_request<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<Request>, HttpError> {
+    let mut searched: usize = 0;
+    let head_end = loop {
+        // Only look at new bytes, but an end marker may straddle two reads
+        let from = searched.saturating_sub(3);
+        if let Some(pos) = find(&buf[from..], b"\r\n\r\n") {
+            break from + pos + 4;
+        }
+        searched = buf.len();
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(HttpError::HeadTooLarge);
+        }
+        if read_more(stream, buf).await? == 0 {
+            // Blank lines between requests are allowed
+            if buf.iter().all(|b| b.is_ascii_whitespace()) {
+                return Ok(None);
+            }
+            return Err(HttpError::UnexpectedEof);
+        }
+    };
+    if head_end > MAX_HEAD_SIZE {
+        return Err(HttpError::HeadTooLarge);
+    }
+    let mut request = parse_head(&buf[..head_end])?;
+    buf.drain(..head_end);
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(HttpError::BadRequest("chunked bodies are not supported"));
+    }
+    request.content_length = match request.header("content-length") {
+        Some(length) => length
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| HttpError::BadRequest("invalid Content-Length"))?,
+        None => 0,
+    };
+    Ok(Some(request))
+}
+
+/// Reads the body of `request` if it is at most `max` bytes large
+pub async fn read_body<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+    request: &mut Request,
+    max: usize,
+) -> Result<(), HttpError> {
+    let length = request.content_length;
+    if length > max {
+        return Err(HttpError::BodyTooLarge);
+    }
+    while buf.len() < length {
+        if read_more(stream, buf).await? == 0 {
+            return Err(HttpError::UnexpectedEof);
+        }
+    }
+    request.body = buf.drain(..length).collect();
+    Ok(())
+}
+
+async fn read_more<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<usize, HttpError> {
+    let mut chunk = [0u8; 4096];
+    let n = stream
+        .read(&mut chunk)
+        .await
+        .map_err(|e| HttpError::Io(e.kind()))?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+/// Parses the request line and headers, `head` ends with the empty line
+fn parse_head(head: &[u8]) -> Result<Request, HttpError> {
+    let head = std::str::from_utf8(head).map_err(|_| HttpError::BadRequest("not UTF-8"))?;
+    // Leading empty lines are allowed before the request line
+    let mut lines = head
+        .trim_start_matches(['\r', '\n'])
+        .split("\r\n")
+        .take_while(|line| !line.is_empty());
+
+    let request_line = lines.next().ok_or(HttpError::BadRequest("missing request line"))?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(HttpError::BadRequest("malformed request line"));
+    };
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(HttpError::BadRequest("invalid method"));
+    }
+    let version = match version {
+        "HTTP/1.0" => 0,
+        "HTTP/1.1" => 1,
+        _ => return Err(HttpError::BadRequest("unsupported HTTP version")),
+    };
+    if !target.starts_with('/') {
+        return Err(HttpError::BadRequest("invalid request target"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(HttpError::BadRequest("malformed header"))?;
+        if name.is_empty() || name.ends_with([' ', '\t']) {
+            return Err(HttpError::BadRequest("malformed header"));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        headers
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    if version == 1 && !headers.contains_key("host") {
+        return Err(HttpError::BadRequest("missing Host header"));
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        version,
+        headers,
+        content_length: 0,
+        body: Vec::new(),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(&[high, low]) if bytes[i] == b'%' => hex(high).zip(hex(low)),
+            _ => None,
+        };
+        match (escaped, bytes[i]) {
+            (Some((high, low)), _) => {
+                out.push(high << 4 | low);
+                i += 2;
+            }
+            (None, b'+') => out.push(b' '),
+            (None, byte) => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// An HTTP response, written with [`Response::write_to`]
+pub(crate) struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn text(self, text: &str) -> Self {
+        self.body("text/plain; charset=utf-8", format!("{}\n", text))
+    }
+
+    /// Writes the response. Without `send_body` (for HEAD requests) only the
+    /// headers are sent. `keep_alive` tells the client whether the connection
+    /// stays open; it's said both ways as HTTP/1.0 clients close by default.
+    pub async fn write_to<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        send_body: bool,
+        keep_alive: bool,
+    ) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // These never have a body, not even an empty one
+        if !matches!(self.status, 101 | 204 | 304) {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        if keep_alive {
+            head.push_str("Connection: keep-alive\r\n");
+        } else {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        if send_body {
+            stream.write_all(&self.body).await?;
+        }
+        stream.flush().await
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Hands out the data in the given pieces, one per read
+    struct Fragmented(Vec<Vec<u8>>);
+
+    impl Fragmented {
+        fn new(pieces: &[&[u8]]) -> Self {
+            Fragmented(pieces.iter().rev().map(|piece| piece.to_vec()).collect())
+        }
+
+        fn bytewise(data: &[u8]) -> Self {
+            Fragmented(data.iter().rev().map(|&b| vec![b]).collect())
+        }
+    }
+
+    impl AsyncRead for Fragmented {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(piece) = self.0.pop() {
+                let n = piece.len().min(buf.remaining());
+                buf.put_slice(&piece[..n]);
+                if n < piece.len() {
+                    self.0.push(piece[n..].to_vec());
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Largest body the tests read
+    const MAX_BODY: usize = 1024;
+
+    async fn read_all(mut stream: Fragmented) -> Vec<Result<Request, HttpError>> {
+        let mut buf = Vec::new();
+        let mut requests = Vec::new();
+        loop {
+            let request = match read_request(&mut stream, &mut buf).await {
+                Ok(Some(mut request)) => read_body(&mut stream, &mut buf, &mut request, MAX_BODY)
+                    .await
+                    .map(|()| request),
+                Ok(None) => return requests,
+                Err(e) => Err(e),
+            };
+            match request {
+                Ok(request) => requests.push(Ok(request)),
+                Err(e) => {
+                    requests.push(Err(e));
+                    return requests;
+                }
+            }
+        }
+    }
+
+    async fn read_one(data: &[u8]) -> Result<Request, HttpError> {
+        read_all(Fragmented::new(&[data])).await.remove(0)
+    }
+
+    #[tokio::test]
+    async fn parses_request_line_and_headers() {
+        let request = read_one(
+            b"GET /api/replay?start=5&speed=2%2E5 HTTP/1.1\r\nHost: x\r\nUPGRADE: WebSocket\r\nX-A: 1\r\nx-a: 2\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/api/replay");
+        assert_eq!(request.version, 1);
+        assert_eq!(request.query_param("start").as_deref(), Some("5"));
+        assert_eq!(request.query_param("speed").as_deref(), Some("2.5"));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.header("x-a"), Some("1, 2"));
+        assert!(request.is_upgrade());
+        assert!(request.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn handles_requests_split_at_every_byte() {
+        let data = b"GET /canvas HTTP/1.1\r\nHost: x\r\n\r\nPOST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.0\r\n\r\n";
+        let requests = read_all(Fragmented::bytewise(data)).await;
+        let requests: Vec<Request> = requests.into_iter().map(Result::unwrap).collect();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/canvas");
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].body, b"hello");
+        assert_eq!(requests[2].path, "/b");
+        assert!(!requests[2].keep_alive());
+    }
+
+    #[tokio::test]
+    async fn handles_the_end_marker_split_between_reads() {
+        let pieces: &[&[u8]] = &[b"GET / HTTP/1.1\r\nHost: x\r", b"\n\r", b"\n"];
+        let requests = read_all(Fragmented::new(pieces)).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].as_ref().unwrap().path, "/");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        for data in [
+            &b"GET\r\n\r\n"[..],
+            b"GET / HTTP/1.1 extra\r\nHost: x\r\n\r\n",
+            b"get / HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET / HTTP/2.0\r\nHost: x\r\n\r\n",
+            b"GET relative HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: x\r\nno colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : x\r\n\r\n",
+            b"GET /\xff HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: abc\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            assert!(
+                matches!(read_one(data).await, Err(HttpError::BadRequest(_))),
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_sizes() {
+        let mut data = b"GET / HTTP/1.1\r\nHost: x\r\nX: ".to_vec();
+        data.extend(std::iter::repeat_n(b'a', MAX_HEAD_SIZE));
+        data.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(read_one(&data).await.unwrap_err(), HttpError::HeadTooLarge);
+
+        let data = format!(
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(
+            read_one(data.as_bytes()).await.unwrap_err(),
+            HttpError::BodyTooLarge
+        );
+    }
+
+    #[tokio::test]
+    async fn distinguishes_closed_connections() {
+        assert!(read_all(Fragmented::new(&[])).await.is_empty());
+        assert!(read_all(Fragmented::new(&[b"\r\n"])).await.is_empty());
+        assert_eq!(
+            read_one(b"GET / HTTP/1.1\r\nHo").await.unwrap_err(),
+            HttpError::UnexpectedEof
+        );
+        assert_eq!(
+            read_one(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nab")
+                .await
+                .unwrap_err(),
+            HttpError::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version_and_connection_header() {
+        let keep_alive = |head: &str| parse_head(head.as_bytes()).unwrap().keep_alive();
+        assert!(keep_alive("GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.1\r\nHost: x\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive("GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn tells_whether_the_connection_stays_open() {
+        let head = |keep_alive: bool| async move {
+            let mut out = Vec::new();
+            let response = Response::new(204);
+            response.write_to(&mut out, true, keep_alive).await.unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(head(true).await.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(head(false).await.contains("\r\nConnection: close\r\n"));
+    }
+}
@@ -0,0 +1,473 @@
+use std::io::{self, Cursor};
+use std::str::FromStr;
+
+use rapid_qoi::{Colors, Qoi};
+use serde::Deserialize;
+
+/// Largest image a scaled export may produce, in pixels
+const MAX_SCALED_PIXELS: u64 = 8192 * 8192;
+const MAX_SCALE: f64 = 16.0;
+
+/// Formats the canvas can be exported as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageFormat {
+    Png,
+    Qoi,
+    /// Binary PPM (P6), without alpha
+    Ppm,
+    /// Raw rgba bytes, row by row
+    Rgba,
+}
+
+impl ImageFormat {
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match extension {
+            "png" => Some(ImageFormat::Png),
+            "qoi" => Some(ImageFormat::Qoi),
+            "ppm" => Some(ImageFormat::Ppm),
+            "rgba" | "raw" => Some(ImageFormat::Rgba),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Qoi => "image/qoi",
+            ImageFormat::Ppm => "image/x-portable-pixmap",
+            ImageFormat::Rgba => "application/octet-stream",
+        }
+    }
+
+    /// Encodes `width`x`height` rgba pixels. `srgb` is the colorspace of the
+    /// canvas, only QOI headers carry it.
+    pub fn encode(self, width: u32, height: u32, rgba: &[u8], srgb: bool) -> io::Result<Vec<u8>> {
+        match self {
+            ImageFormat::Png => {
+                let mut buf = Vec::new();
+                let mut encoder = png::Encoder::new(&mut buf, width, height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .write_header()
+                    .and_then(|mut png| png.write_image_data(rgba))
+                    .map_err(io::Error::other)?;
+                Ok(buf)
+            }
+            // Always with alpha, like the cached QOI of the whole canvas, so
+            // every QOI export of a canvas has the same header
+            ImageFormat::Qoi => Qoi {
+                width,
+                height,
+                colors: if srgb { Colors::SrgbLinA } else { Colors::Rgba },
+            }
+            .encode_alloc(rgba)
+            .map_err(io::Error::other),
+            ImageFormat::Ppm => {
+                let mut buf = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+                buf.reserve(rgba.len() / 4 * 3);
+                for pixel in rgba.chunks_exact(4) {
+                    buf.extend_from_slice(&pixel[..3]);
+                }
+                Ok(buf)
+            }
+            ImageFormat::Rgba => Ok(rgba.to_vec()),
+        }
+    }
+}
+
+/// A decoded image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+    /// Whether the color channels are tagged as sRGB instead of linear, only
+    /// QOI headers carry this
+    pub srgb: bool,
+}
+
+impl Image {
+    fn new(width: u32, height: u32, rgba: Vec<u8>) -> Image {
+        Image {
+            width,
+            height,
+            rgba,
+            srgb: false,
+        }
+    }
+}
+
+/// Decodes a QOI, PNG or binary PPM image. The format is detected from the
+/// first bytes. Images without pixels are rejected, they can't be fitted.
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let image = decode_any(data)?;
+    if image.width == 0 || image.height == 0 {
+        return Err(format!("Image is empty ({}x{})", image.width, image.height));
+    }
+    Ok(image)
+}
+
+fn decode_any(data: &[u8]) -> Result<Image, String> {
+    if data.starts_with(b"qoif") {
+        decode_qoi(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        decode_png(data)
+    } else if data.starts_with(b"P6") {
+        decode_ppm(data)
+    } else if data.starts_with(b"P3") {
+        Err("Only binary PPM images (P6) are supported".to_string())
+    } else {
+        Err("Unknown image format, expected QOI, PNG or PPM".to_string())
+    }
+}
+
+fn decode_qoi(data: &[u8]) -> Result<Image, String> {
+    let (qoi, pixels) =
+        Qoi::decode_alloc(data).map_err(|e| format!("Invalid QOI image: {:?}", e))?;
+    let rgba = match qoi.colors.has_alpha() {
+        true => pixels,
+        false => pixels
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect(),
+    };
+    // The colorspace is only metadata, the pixels are the same either way
+    Ok(Image {
+        srgb: matches!(qoi.colors, Colors::Srgb | Colors::SrgbLinA),
+        ..Image::new(qoi.width, qoi.height, rgba)
+    })
+}
+
+fn decode_png(data: &[u8]) -> Result<Image, String> {
+    let invalid = |e: png::DecodingError| format!("Invalid PNG image: {}", e);
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    // Palettes and low bit depths are expanded, 16 bit channels cut to 8 bit
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(invalid)?;
+    let size = reader
+        .output_buffer_size()
+        .ok_or_else(|| "PNG image is too large".to_string())?;
+    let mut pixels = vec![0; size];
+    let info = reader.next_frame(&mut pixels).map_err(invalid)?;
+    pixels.truncate(info.buffer_size());
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => pixels,
+        png::ColorType::Rgb => pixels
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+            .collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        color_type => return Err(format!("Unsupported PNG color type {:?}", color_type)),
+    };
+    Ok(Image::new(info.width, info.height, rgba))
+}
+
+/// Binary PPM: "P6", width, height and the maximum value as ascii numbers
+/// separated by whitespace (and comments), then a single whitespace and the
+/// rgb pixels with one byte per channel, or two if the maximum is above 255
+fn decode_ppm(data: &[u8]) -> Result<Image, String> {
+    let mut pos = 2;
+    let mut header = [0u32; 3];
+    for value in &mut header {
+        loop {
+            match data.get(pos) {
+                Some(b'#') => {
+                    while data.get(pos).is_some_and(|&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => pos += 1,
+                _ => break,
+            }
+        }
+        let start = pos;
+        while data.get(pos).is_some_and(u8::is_ascii_digit) {
+            pos += 1;
+        }
+        *value = std::str::from_utf8(&data[start..pos])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| "Invalid PPM header".to_string())?;
+    }
+    let [width, height, max] = header;
+    if !data.get(pos).is_some_and(u8::is_ascii_whitespace) || max == 0 || max > 65535 {
+        return Err("Invalid PPM header".to_string());
+    }
+    let pixels = &data[pos + 1..];
+    let channel_size = if max > 255 { 2 } else { 1 };
+    let len = width as u64 * height as u64 * 3 * channel_size;
+    if (pixels.len() as u64) < len {
+        return Err(format!(
+            "PPM image is truncated, expected {} bytes of pixels",
+            len
+        ));
+    }
+    let rgba = pixels[..len as usize]
+        .chunks_exact(3 * channel_size as usize)
+        .flat_map(|pixel| {
+            let channel = |i: usize| match channel_size {
+                1 => pixel[i] as u32 * 255 / max,
+                _ => u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]) as u32 * 255 / max,
+            };
+            [channel(0) as u8, channel(1) as u8, channel(2) as u8, 255]
+        })
+        .collect();
+    Ok(Image::new(width, height, rgba))
+}
+
+/// How an image of a different size is fitted into the canvas
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Fit {
+    /// The canvas takes the size of the image
+    #[default]
+    Keep,
+    /// The image is stretched to the canvas size
+    Stretch,
+    /// The image is scaled to fit the canvas, keeping its aspect ratio and
+    /// filling the rest with black
+    Letterbox,
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Fit, String> {
+        match value {
+            "keep" => Ok(Fit::Keep),
+            "stretch" => Ok(Fit::Stretch),
+            "letterbox" => Ok(Fit::Letterbox),
+            _ => Err("expected keep, stretch or letterbox".to_string()),
+        }
+    }
+}
+
+impl Fit {
+    /// Fits an image into `canvas_width`x`canvas_height`
+    pub fn apply(self, image: Image, (canvas_width, canvas_height): (u32, u32)) -> Image {
+        let (width, height) = (image.width, image.height);
+        if self == Fit::Keep || (width, height) == (canvas_width, canvas_height) {
+            return image;
+        }
+        let (scaled_width, scaled_height) = match self {
+            Fit::Letterbox => {
+                let scale =
+                    (canvas_width as f64 / width as f64).min(canvas_height as f64 / height as f64);
+                (
+                    ((width as f64 * scale).round() as u32).clamp(1, canvas_width),
+                    ((height as f64 * scale).round() as u32).clamp(1, canvas_height),
+                )
+            }
+            _ => (canvas_width, canvas_height),
+        };
+        let (left, top) = (
+            (canvas_width - scaled_width) / 2,
+            (canvas_height - scaled_height) / 2,
+        );
+        let mut canvas = [0, 0, 0, 255].repeat(canvas_width as usize * canvas_height as usize);
+        for y in 0..scaled_height {
+            let src_y = (y as u64 * height as u64 / scaled_height as u64) as usize;
+            for x in 0..scaled_width {
+                let src_x = (x as u64 * width as u64 / scaled_width as u64) as usize;
+                let src = (src_x + src_y * width as usize) * 4;
+                let dst = ((left + x) as usize + (top + y) as usize * canvas_width as usize) * 4;
+                canvas[dst..dst + 4].copy_from_slice(&image.rgba[src..src + 4]);
+            }
+        }
+        Image {
+            srgb: image.srgb,
+            ..Image::new(canvas_width, canvas_height, canvas)
+        }
+    }
+}
+
+/// The part of the canvas an export shows and the size it is scaled to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct View {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub out_width: u32,
+    pub out_height: u32,
+}
+
+impl View {
+    /// Builds a view of a `canvas_width`x`canvas_height` canvas. The crop
+    /// defaults to the whole canvas, or to everything right of and below
+    /// `x`,`y`. `scale` scales the crop with nearest neighbour sampling.
+    pub fn new(
+        (canvas_width, canvas_height): (u32, u32),
+        x: Option<u32>,
+        y: Option<u32>,
+        width: Option<u32>,
+        height: Option<u32>,
+        scale: Option<f64>,
+    ) -> Result<View, String> {
+        let (x, y) = (x.unwrap_or(0), y.unwrap_or(0));
+        if x >= canvas_width || y >= canvas_height {
+            return Err(format!(
+                "Crop origin {},{} is outside of the {}x{} canvas",
+                x, y, canvas_width, canvas_height
+            ));
+        }
+        let width = width.unwrap_or(canvas_width - x);
+        let height = height.unwrap_or(canvas_height - y);
+        if width == 0 || height == 0 || width > canvas_width - x || height > canvas_height - y {
+            return Err(format!(
+                "Crop {}x{} at {},{} does not fit into the {}x{} canvas",
+                width, height, x, y, canvas_width, canvas_height
+            ));
+        }
+        let scale = scale.unwrap_or(1.0);
+        if !(scale > 0.0 && scale <= MAX_SCALE) {
+            return Err(format!(
+                "Scale must be greater than 0 and at most {}",
+                MAX_SCALE
+            ));
+        }
+        let out_width = ((width as f64 * scale).round() as u32).max(1);
+        let out_height = ((height as f64 * scale).round() as u32).max(1);
+        if scale > 1.0 && out_width as u64 * out_height as u64 > MAX_SCALED_PIXELS {
+            return Err(format!(
+                "Scaled image must not exceed {} pixels",
+                MAX_SCALED_PIXELS
+            ));
+        }
+        Ok(View {
+            x,
+            y,
+            width,
+            height,
+            out_width,
+            out_height,
+        })
+    }
+
+    /// Whether the view shows the whole canvas unchanged
+    pub fn is_full(&self, (canvas_width, canvas_height): (u32, u32)) -> bool {
+        (self.x, self.y) == (0, 0)
+            && (self.width, self.height) == (canvas_width, canvas_height)
+            && (self.out_width, self.out_height) == (canvas_width, canvas_height)
+    }
+
+    /// Crops and scales the rgba pixels of a canvas that is `canvas_width` wide
+    pub fn apply(&self, canvas_width: u32, rgba: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.out_width as usize * self.out_height as usize * 4);
+        for out_y in 0..self.out_height {
+            let y = self.y + (out_y as u64 * self.height as u64 / self.out_height as u64) as u32;
+            let row = y as usize * canvas_width as usize;
+            if self.out_width == self.width {
+                let start = (row + self.x as usize) * 4;
+                buf.extend_from_slice(&rgba[start..start + self.width as usize * 4]);
+                continue;
+            }
+            for out_x in 0..self.out_width {
+                let x = self.x + (out_x as u64 * self.width as u64 / self.out_width as u64) as u32;
+                let i = (row + x as usize) * 4;
+                buf.extend_from_slice(&rgba[i..i + 4]);
+            }
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 3x2 canvas, the red channel holds the pixel index
+    fn canvas() -> Vec<u8> {
+        (0..6).flat_map(|i| [i, 0, 0, 255]).collect()
+    }
+
+    fn reds(rgba: &[u8]) -> Vec<u8> {
+        rgba.chunks_exact(4).map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn crop_and_scale() {
+        let view = View::new((3, 2), Some(1), None, None, None, None).unwrap();
+        assert_eq!((view.out_width, view.out_height), (2, 2));
+        assert_eq!(reds(&view.apply(3, &canvas())), [1, 2, 4, 5]);
+
+        let view = View::new((3, 2), Some(2), Some(1), None, None, Some(2.0)).unwrap();
+        assert_eq!(reds(&view.apply(3, &canvas())), [5, 5, 5, 5]);
+
+        let view = View::new((3, 2), None, None, Some(2), Some(2), Some(0.5)).unwrap();
+        assert_eq!(reds(&view.apply(3, &canvas())), [0]);
+
+        assert!(View::new((3, 2), None, None, None, None, None)
+            .unwrap()
+            .is_full((3, 2)));
+    }
+
+    #[test]
+    fn rejects_views_outside_of_the_canvas() {
+        assert!(View::new((3, 2), Some(3), None, None, None, None).is_err());
+        assert!(View::new((3, 2), Some(1), None, Some(3), None, None).is_err());
+        assert!(View::new((3, 2), None, None, Some(0), None, None).is_err());
+        assert!(View::new((3, 2), None, None, None, None, Some(0.0)).is_err());
+        assert!(View::new((3, 2), None, None, None, None, Some(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn decodes_by_magic_bytes() {
+        let rgba = [1, 2, 3, 4, 5, 6, 7, 255];
+        let png = ImageFormat::Png.encode(2, 1, &rgba, false).unwrap();
+        assert_eq!(decode(&png).unwrap(), Image::new(2, 1, rgba.to_vec()));
+        let qoi = ImageFormat::Qoi.encode(2, 1, &rgba, false).unwrap();
+        assert_eq!(decode(&qoi).unwrap(), Image::new(2, 1, rgba.to_vec()));
+
+        let ppm = b"P6\n# comment\n2 1 255\n\x01\x02\x03\x04\x05\x06";
+        let image = Image::new(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(decode(ppm).unwrap(), image);
+        let ppm16 = b"P6 1 1 65535 \xff\xff\x00\x00\x80\x00";
+        assert_eq!(decode(ppm16).unwrap(), Image::new(1, 1, vec![255, 0, 127, 255]));
+
+        assert!(decode(b"P6\n2 1 255\n\x01\x02").is_err());
+        assert!(decode(b"GIF89a").is_err());
+        // Nothing to fit into a canvas
+        assert!(decode(b"P6 0 5 255 ").is_err());
+        assert!(decode(b"P6 5 0 255 ").is_err());
+        assert!(decode(b"qoif broken").is_err());
+    }
+
+    #[test]
+    fn fits_images_into_the_canvas() {
+        let image = Image::new(2, 1, vec![1, 0, 0, 255, 2, 0, 0, 255]);
+        assert_eq!(Fit::Keep.apply(image.clone(), (4, 4)), image);
+
+        let stretched = Fit::Stretch.apply(image.clone(), (4, 2));
+        assert_eq!((stretched.width, stretched.height), (4, 2));
+        assert_eq!(reds(&stretched.rgba), [1, 1, 2, 2, 1, 1, 2, 2]);
+
+        // Centered with black bars above and below
+        let letterboxed = Fit::Letterbox.apply(image, (2, 3));
+        assert_eq!(reds(&letterboxed.rgba), [0, 0, 1, 2, 0, 0]);
+        assert_eq!(letterboxed.rgba[3], 255);
+    }
+
+    #[test]
+    fn qoi_keeps_the_colorspace() {
+        let opaque = [1, 2, 3, 255, 4, 5, 6, 255];
+        for srgb in [false, true] {
+            let qoi = ImageFormat::Qoi.encode(2, 1, &opaque, srgb).unwrap();
+            // Channels and colorspace in the header
+            assert_eq!(qoi[12..14], [4, if srgb { 0 } else { 1 }]);
+            let image = decode(&qoi).unwrap();
+            assert_eq!((image.rgba, image.srgb), (opaque.to_vec(), srgb));
+        }
+    }
+
+    #[test]
+    fn ppm_drops_alpha() {
+        let ppm = ImageFormat::Ppm.encode(1, 1, &[1, 2, 3, 4], false).unwrap();
+        assert_eq!(ppm, b"P6\n1 1\n255\n\x01\x02\x03");
+    }
+}
@@ -1,50 +1,362 @@
 use std::sync::Arc;
 use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::runtime;
 
-use crate::color::Color;
+use crate::access::AccessList;
+use crate::admin::Admin;
+use crate::canvases::{Canvases, NamedCanvas, MAIN_CANVAS};
+use crate::config::Config;
+use crate::connections::{ConnectionGuard, Connections};
+use crate::history::History;
+use crate::image::Fit;
+use crate::metrics::METRICS;
+use crate::overlay::Region;
 use crate::pixel_map::PixelMap;
+use crate::protocol::Command;
+use crate::rate_limit::{RateLimiter, Throttle};
+use crate::shutdown::{Shutdown, ShutdownSignal};
+use crate::snapshot::Snapshots;
+use crate::static_files::StaticFiles;
+use crate::timelapse::Recorder;
 
+mod access;
+mod admin;
+mod attribution;
+mod binary;
+mod canvases;
 mod color;
+mod config;
+mod connections;
+mod history;
+mod http;
+mod image;
+mod metrics;
+mod overlay;
 mod pixel_map;
+mod protocol;
+mod rate_limit;
 mod render_thread;
+mod shutdown;
+mod snapshot;
+mod static_files;
+mod timelapse;
 
 fn main() {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("export-timelapse") {
+        if let Err(e) = timelapse::export_command(&args[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = config.workers {
+        builder.worker_threads(workers);
+    }
+    let runtime = builder.enable_all().build().unwrap();
+
+    let mut canvases = match open_canvas(&config, MAIN_CANVAS, config.import.as_deref(), None) {
+        Ok(canvas) => Canvases::new(canvas),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for spec in &config.canvases {
+        match open_canvas(&config, &spec.name, None, spec.size) {
+            Ok(canvas) => canvases.add(canvas),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let canvases = Arc::new(canvases);
+    for canvas in canvases.iter() {
+        runtime.spawn(Arc::clone(&canvas.snapshots).run(
+            Arc::clone(&canvas.pixel_map),
+            Duration::from_secs(config.snapshot_interval),
+        ));
+    }
+    // Timelapse and history only follow the main canvas
+    let pixel_map = Arc::clone(&canvases.main().pixel_map);
+
+    let recorder = config.timelapse.as_ref().map(|path| {
+        let recorder = match Recorder::open(path) {
+            Ok(recorder) => Arc::new(recorder),
+            Err(e) => {
+                eprintln!("Could not open timelapse {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        runtime.spawn(Arc::clone(&recorder).run(
+            Arc::clone(&pixel_map),
+            Duration::from_secs(config.timelapse_interval),
+        ));
+        recorder
+    });
+
+    let history = config.history.as_ref().map(|dir| {
+        let retention = Duration::from_secs(config.history_retention * 60 * 60);
+        let history = match History::open(dir, retention) {
+            Ok(history) => Arc::new(history),
+            Err(e) => {
+                eprintln!("Could not open history {}: {}", dir, e);
+                std::process::exit(1);
+            }
+        };
+        runtime.spawn(Arc::clone(&history).run(
+            Arc::clone(&pixel_map),
+            Duration::from_secs(config.history_interval),
+        ));
+        history
+    });
+
+    let static_files = config.static_dir.as_ref().map(|dir| match StaticFiles::new(dir) {
+        Ok(static_files) => static_files,
+        Err(e) => {
+            eprintln!("Could not serve static files from {}: {}", dir, e);
+            std::process::exit(1);
+        }
+    });
 
-    let handle = runtime.handle().clone();
+    let rate_limiter = Arc::new(RateLimiter::new(
+        config.pixel_rate,
+        config.ip_pixel_rate,
+        config.total_pixel_rate,
+    ));
 
-    let pixel_map = Arc::new(PixelMap::load_image("image.qoi"));
+    let connections = Arc::new(Connections::new(
+        config.max_connections,
+        config.max_connections_per_ip,
+    ));
 
-    let pix_clone = Arc::clone(&pixel_map);
+    let access = match AccessList::open(config.ban_list.as_deref(), config.allow_list.as_deref()) {
+        Ok(access) => Arc::new(access),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.spawn(Arc::clone(&access).run(Arc::clone(&connections), access::RELOAD_INTERVAL));
+
+    let shutdown = Shutdown::new();
 
-    runtime.spawn(async move {
-        let tcp_listener = TcpListener::bind("0.0.0.0:1337").await.unwrap();
-        loop {
-            let (socket, _) = tcp_listener.accept().await.unwrap();
-            let pixel_map = Arc::clone(&pixel_map);
-            tokio::spawn(async move {
-                handle_connection(socket, pixel_map).await;
-            });
+    runtime.spawn(serve_pixelflut(
+        config.listen.clone(),
+        Arc::clone(canvases.main()),
+        Arc::clone(&canvases),
+        Arc::clone(&connections),
+        Arc::clone(&access),
+        Arc::clone(&rate_limiter),
+        shutdown.signal(),
+    ));
+    for spec in &config.canvases {
+        if let Some(listen) = spec.listen_address(&config.listen) {
+            runtime.spawn(serve_pixelflut(
+                listen,
+                Arc::clone(canvases.get(&spec.name).unwrap()),
+                Arc::clone(&canvases),
+                Arc::clone(&connections),
+                Arc::clone(&access),
+                Arc::clone(&rate_limiter),
+                shutdown.signal(),
+            ));
+        }
+    }
+
+    let http = runtime.spawn(render_thread::render_thread(
+        Arc::clone(&canvases),
+        config.http_listen,
+        config.tick_rate,
+        history.clone(),
+        static_files,
+        config.admin_token.map(|token| {
+            Admin::new(
+                token,
+                Arc::clone(&canvases),
+                Arc::clone(&connections),
+                Arc::clone(&access),
+                config.max_upload * 1024 * 1024,
+            )
+        }),
+        Arc::clone(&access),
+        shutdown.signal(),
+    ));
+
+    runtime.block_on(async {
+        tokio::select! {
+            name = shutdown::wait_for_signal() => println!("Received {}, shutting down", name),
+            _ = http => {
+                println!("HTTP server stopped unexpectedly");
+                std::process::exit(1);
+            }
+        }
+        if !shutdown.drain(SHUTDOWN_TIMEOUT).await {
+            println!("Some clients did not disconnect in time, closing them");
         }
     });
+    // Stops everything that is still running, so nothing changes the canvas after the final save
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+
+    let mut saved = true;
+    for canvas in canvases.iter() {
+        let snapshots = &canvas.snapshots;
+        match snapshots.save(&canvas.pixel_map) {
+            Ok(true) => println!("Saved final snapshot to {}", snapshots.path().display()),
+            Ok(false) => println!("Snapshot {} is up to date", snapshots.path().display()),
+            Err(e) => {
+                println!(
+                    "Failed to save final snapshot to {}: {}",
+                    snapshots.path().display(),
+                    e
+                );
+                saved = false;
+            }
+        }
+    }
+    if !saved {
+        std::process::exit(1);
+    }
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.record(&pixel_map) {
+            println!("Failed to record the last timelapse frame: {}", e);
+        }
+    }
+    if let Some(history) = history {
+        if let Err(e) = history.record(&pixel_map) {
+            println!("Failed to record the last history frame: {}", e);
+        }
+    }
+    println!("Shutdown complete");
+}
 
-    runtime.block_on(render_thread::render_thread(pix_clone, handle));
+/// Restores a canvas from its snapshot. Without a snapshot the canvas starts
+/// from the imported image, or black if there is none. `size` overrides the
+/// size of the main canvas.
+fn open_canvas(
+    config: &Config,
+    name: &str,
+    import: Option<&str>,
+    size: Option<(u32, u32)>,
+) -> Result<NamedCanvas, String> {
+    let snapshot = canvases::snapshot_path(&config.snapshot, name);
+    let (width, height) = size.unwrap_or((config.width, config.height));
+    // A snapshot is restored as it is, only imports are fitted. An empty
+    // file counts as no snapshot, as left behind by `touch`.
+    let source = match std::fs::metadata(&snapshot) {
+        Ok(metadata) if metadata.len() > 0 => Some((snapshot.as_str(), Fit::Keep)),
+        Ok(_) => import.map(|import| (import, config.fit)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            import.map(|import| (import, config.fit))
+        }
+        Err(e) => return Err(format!("Could not read {}: {}", snapshot, e)),
+    };
+    let mut pixel_map = match source {
+        Some((path, fit)) => {
+            let pixel_map = PixelMap::load_image(path, width, height, fit)?;
+            let (width, height) = pixel_map.get_size();
+            println!("Loaded {}x{} canvas {} from {}", width, height, name, path);
+            pixel_map
+        }
+        None => {
+            println!("No snapshot at {}, starting with a fresh canvas", snapshot);
+            PixelMap::new(width, height)
+        }
+    };
+    let regions = config.protect.iter().filter(|spec| spec.canvas == name);
+    pixel_map.set_overlay(regions.map(Region::load).collect::<Result<_, _>>()?);
+    if config.attribution {
+        pixel_map.enable_attribution();
+    }
+    let snapshots = Snapshots::new(&snapshot, config.snapshot_keep, pixel_map.version());
+    Ok(NamedCanvas {
+        name: name.to_string(),
+        pixel_map: Arc::new(pixel_map),
+        snapshots: Arc::new(snapshots),
+    })
 }
 
-async fn handle_connection(mut socket: TcpStream, mut pixel_map: Arc<PixelMap>) {
-    let width: u32 = pixel_map.get_width();
-    let height: u32 = pixel_map.get_height();
+/// Accepts pixelflut clients on `listen`, they start out drawing on `canvas`
+async fn serve_pixelflut(
+    listen: String,
+    canvas: Arc<NamedCanvas>,
+    canvases: Arc<Canvases>,
+    connections: Arc<Connections>,
+    access: Arc<AccessList>,
+    rate_limiter: Arc<RateLimiter>,
+    mut signal: ShutdownSignal,
+) {
+    let tcp_listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Could not listen on {} for canvas {}: {}", listen, canvas.name, e);
+            std::process::exit(1);
+        }
+    };
+    loop {
+        let (socket, addr) = tokio::select! {
+            connection = connections::accept(&tcp_listener) => connection,
+            _ = signal.recv() => return,
+        };
+        let guard = match access.check(addr.ip()).and_then(|()| connections.open(addr)) {
+            Ok(guard) => guard,
+            Err(rejection) => {
+                tokio::spawn(connections::reject(socket, rejection));
+                continue;
+            }
+        };
+        let canvas = Arc::clone(&canvas);
+        let canvases = Arc::clone(&canvases);
+        let throttle = rate_limiter.connect(addr.ip());
+        let signal = signal.clone();
+        tokio::spawn(handle_connection(
+            socket, canvases, canvas, throttle, guard, signal,
+        ));
+    }
+}
+
+/// How long clients get to disconnect on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const RATE_LIMITED: &[u8] = b"ERR: Rate Limited (Tip: slow down)\n";
+const PROTECTED: &[u8] = b"ERR: Protected Area (Tip: draw somewhere else)\n";
+const READ_ONLY: &[u8] = b"ERR: Read Only (Tip: wait until the canvas opens again)\n";
+const NOT_ATTRIBUTED: &[u8] = b"ERR: Attribution Disabled (Tip: ask the server admin)\n";
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    canvases: Arc<Canvases>,
+    mut canvas: Arc<NamedCanvas>,
+    mut throttle: Throttle,
+    // Counts the connection while it is open
+    guard: ConnectionGuard,
+    mut shutdown: ShutdownSignal,
+) {
+    // Changes when the canvas is resized, the client is told the new size
+    let mut size = canvas.pixel_map.watch_size();
+    let (mut width, mut height) = *size.borrow_and_update();
     let mut binary = false;
     let mut debug = false;
+    // Added to the coordinates of every PX, set with OFFSET x y
+    let mut offset: (u32, u32) = (0, 0);
+    // Everything drawn over this connection is attributed to it
+    let writer = guard.writer();
     let (read_half, mut write_half) = socket.split();
-    let mut message = String::new();
+    let mut message = Vec::new();
     /* Binary Message Buffer
     // Format:
     // [u16: x][u16: y][u32: rgba]
@@ -66,49 +378,88 @@ async fn handle_connection(mut socket: TcpStream, mut pixel_map: Arc<PixelMap>)
     //
     // 55.56% less data
      */
-    let mut bin_buf: [u8; 8] = [0; 8];
+    let mut bin_buf = vec![0u8; binary::CHUNK_SIZE];
+    // Bytes of an incomplete record left at the start of bin_buf
+    let mut pending = 0;
+    // The binary mode has no replies, so a read only canvas is only reported once
+    let mut told_read_only = false;
+    let mut kicked = false;
     let mut reader = BufReader::new(read_half);
-    loop {
-        let pixel_map = &mut pixel_map;
+    'connection: loop {
+        let pixel_map = &canvas.pixel_map;
         message.clear();
         if binary {
-            let bin_read_length = reader.read_exact(&mut bin_buf).await;
-            match bin_read_length {
-                Ok(e) => {
-                    if e != 8 {
-                        write_half
-                            .write_all("ERR: Invalid Binary Length\n".as_bytes())
-                            .await
-                            .unwrap();
+            let read = loop {
+                tokio::select! {
+                    read = reader.read(&mut bin_buf[pending..]) => break read,
+                    Ok(()) = size.changed() => {
+                        (width, height) = *size.borrow_and_update();
+                        let notice = format!("SIZE {} {}\n", width, height);
+                        write_half.write_all(notice.as_bytes()).await.unwrap_or(());
+                    }
+                    _ = shutdown.recv() => break 'connection,
+                    _ = guard.kicked() => {
+                        kicked = true;
+                        break 'connection;
+                    }
+                }
+            };
+            match read {
+                Ok(0) => break,
+                Ok(n) => {
+                    // Apply all complete records, keep a partial one for the next read
+                    let end = pending + n;
+                    let complete = end - end % binary::RECORD_SIZE;
+                    if pixel_map.is_read_only() {
+                        bin_buf.copy_within(complete..end, 0);
+                        pending = end - complete;
+                        if !told_read_only && write_half.write_all(READ_ONLY).await.is_err() {
+                            break;
+                        }
+                        told_read_only = true;
                         continue;
                     }
-                    let x = u16::from_le_bytes([bin_buf[0], bin_buf[1]]) as u32;
-                    let y = u16::from_le_bytes([bin_buf[2], bin_buf[3]]) as u32;
-                    if x >= width || y >= height {
-                        write_half
+                    told_read_only = false;
+                    let records = (complete / binary::RECORD_SIZE) as u64;
+                    match throttle.take(records, must_leave(&guard, &mut shutdown)).await {
+                        Some(true) => write_half.write_all(RATE_LIMITED).await.unwrap_or(()),
+                        Some(false) => {}
+                        // Unless the server is shutting down, which is handled first
+                        None => {
+                            kicked = true;
+                            break 'connection;
+                        }
+                    }
+                    let mut log = debug.then(String::new);
+                    let skipped = binary::apply_records(
+                        pixel_map,
+                        &bin_buf[..complete],
+                        offset,
+                        writer,
+                        log.as_mut(),
+                    );
+                    bin_buf.copy_within(complete..end, 0);
+                    pending = end - complete;
+                    if let Some(log) = log {
+                        write_half.write_all(log.as_bytes()).await.unwrap_or(());
+                        print!("{}", log);
+                    }
+                    let applied =
+                        complete / binary::RECORD_SIZE - skipped.out_of_bounds - skipped.protected;
+                    METRICS
+                        .pixels_set_binary
+                        .fetch_add(applied as u64, Relaxed);
+                    if skipped.out_of_bounds > 0
+                        && write_half
                             .write_all("ERR: Out of Bounds (Tip: SIZE)\n".as_bytes())
                             .await
-                            .unwrap();
-                        continue;
+                            .is_err()
+                    {
+                        break;
                     }
-                    let color = Color::new(u32::from_le_bytes([
-                        bin_buf[4], bin_buf[5], bin_buf[6], bin_buf[7],
-                    ]));
-                    let mut original_color = pixel_map.get_color(x, y);
-                    original_color.overlay_mut(color);
-                    if debug {
-                        write_half
-                            .write_all(format!("PX {} {} {}\n", x, y, color.hex()).as_bytes())
-                            .await
-                            .unwrap_or(());
-                        println!("PX {} {} {}", x, y, color.hex());
+                    if skipped.protected > 0 && write_half.write_all(PROTECTED).await.is_err() {
+                        break;
                     }
-                    if original_color.equals(pixel_map.get_color(x, y)) {
-                        continue;
-                    }
-                    pixel_map
-                        .get_pixel(x, y)
-                        .store(original_color.raw(), Relaxed);
                 }
                 Err(e) => {
                     println!("Error: {}", e);
@@ -117,111 +468,308 @@ async fn handle_connection(mut socket: TcpStream, mut pixel_map: Arc<PixelMap>)
             }
             continue;
         }
-        match reader.read_line(&mut message).await {
+        // Reading a line can be interrupted and resumed, the partial line stays in `message`
+        let read = loop {
+            tokio::select! {
+                read = reader.read_until(b'\n', &mut message) => break read,
+                Ok(()) = size.changed() => {
+                    (width, height) = *size.borrow_and_update();
+                    let notice = format!("SIZE {} {}\n", width, height);
+                    write_half.write_all(notice.as_bytes()).await.unwrap_or(());
+                }
+                _ = shutdown.recv() => break 'connection,
+                _ = guard.kicked() => {
+                    kicked = true;
+                    break 'connection;
+                }
+            }
+        };
+        match read {
             Ok(0) => break,
             Ok(_) => {
-                let message = message.trim();
-                let mut split = message.split(' ');
-                let command = split.next().unwrap();
+                let command = match protocol::parse(&message) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        if write_half
+                            .write_all(format!("{}\n", e).as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                };
                 match command {
-                    "PX" => {
-                        let x: u32 = match split.next() {
-                            Some(x) => x.parse::<u32>().unwrap(),
-                            None => {
-                                write_half
-                                    .write_all("ERR: Missing X\n".as_bytes())
-                                    .await
-                                    .unwrap();
-                                continue;
-                            }
-                        };
-                        let y: u32 = match split.next() {
-                            Some(y) => y.parse::<u32>().unwrap(),
-                            None => {
-                                write_half
-                                    .write_all("ERR: Missing Y\n".as_bytes())
-                                    .await
-                                    .unwrap();
-                                continue;
-                            }
-                        };
+                    Command::Px { x, y, color } => {
+                        let (x, y) = (x.saturating_add(offset.0), y.saturating_add(offset.1));
                         match (x, y) {
                             coords if coords.0 == width || coords.1 == height => {
-                                write_half
+                                if write_half
                                     .write_all("ERR: 0 based index...\n".as_bytes())
                                     .await
-                                    .unwrap();
+                                    .is_err()
+                                {
+                                    break;
+                                }
                                 continue;
                             }
                             coords if coords.0 > width || coords.1 > height => {
-                                write_half
+                                if write_half
                                     .write_all("ERR: Out of Bounds (Tip: SIZE)\n".as_bytes())
                                     .await
-                                    .unwrap();
+                                    .is_err()
+                                {
+                                    break;
+                                }
                                 continue;
                             }
                             _ => {}
                         };
-                        let next = split.next();
-                        if next.is_none() {
-                            write_half
-                                .write_all(
-                                    format!("PX {} {} {}\n", x, y, pixel_map.get_color(x, y))
-                                        .as_bytes(),
-                                )
-                                .await
-                                .unwrap();
+                        let color = match color {
+                            Some(color) => color,
+                            None => {
+                                // Can only be outside if the canvas shrank just now
+                                let reply = match pixel_map.get_color(x, y) {
+                                    Some(current) => {
+                                        METRICS.pixels_read.fetch_add(1, Relaxed);
+                                        let (x, y) = (x - offset.0, y - offset.1);
+                                        format!("PX {} {} {}\n", x, y, current)
+                                    }
+                                    None => "ERR: Out of Bounds (Tip: SIZE)\n".to_string(),
+                                };
+                                if write_half.write_all(reply.as_bytes()).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+                        if pixel_map.is_read_only() {
+                            if write_half.write_all(READ_ONLY).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        if pixel_map.is_protected(x, y) {
+                            if write_half.write_all(PROTECTED).await.is_err() {
+                                break;
+                            }
                             continue;
                         }
-                        let hex_color = next.unwrap();
-                        let color = Color::from_hex(hex_color).unwrap();
-                        let mut original_color = pixel_map.get_color(x, y);
-                        original_color.overlay_mut(color);
+                        match throttle.take(1, must_leave(&guard, &mut shutdown)).await {
+                            Some(true) => write_half.write_all(RATE_LIMITED).await.unwrap_or(()),
+                            Some(false) => {}
+                            // Unless the server is shutting down, which is handled first
+                            None => {
+                                kicked = true;
+                                break 'connection;
+                            }
+                        }
                         if debug {
                             write_half
-                                .write_all(format!("PX {} {} {}\n", x, y, hex_color).as_bytes())
+                                .write_all(format!("PX {} {} {}\n", x, y, color.hex()).as_bytes())
                                 .await
                                 .unwrap_or(());
-                            println!("PX {} {} {}", x, y, hex_color);
+                            println!("PX {} {} {}", x, y, color.hex());
+                        }
+                        pixel_map.blend_color(x, y, color, writer);
+                        METRICS.pixels_set_text.fetch_add(1, Relaxed);
+                    }
+                    Command::Rect { x, y, w, h, color } => {
+                        if pixel_map.is_read_only() {
+                            if write_half.write_all(READ_ONLY).await.is_err() {
+                                break;
+                            }
+                            continue;
                         }
-                        if original_color.equals(pixel_map.get_color(x, y)) {
+                        let (x, y) = (x.saturating_add(offset.0), y.saturating_add(offset.1));
+                        if x >= width || y >= height {
+                            if write_half
+                                .write_all("ERR: Out of Bounds (Tip: SIZE)\n".as_bytes())
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
                             continue;
                         }
-                        pixel_map
-                            .get_pixel(x, y)
-                            .store(original_color.raw(), Relaxed);
+                        let area = w.min(width - x) as u64 * h.min(height - y) as u64;
+                        match throttle.take(area, must_leave(&guard, &mut shutdown)).await {
+                            Some(true) => write_half.write_all(RATE_LIMITED).await.unwrap_or(()),
+                            Some(false) => {}
+                            // Unless the server is shutting down, which is handled first
+                            None => {
+                                kicked = true;
+                                break 'connection;
+                            }
+                        }
+                        // The rest of the rectangle is still drawn
+                        if pixel_map.overlaps_protected(x, y, w, h)
+                            && write_half.write_all(PROTECTED).await.is_err()
+                        {
+                            break;
+                        }
+                        pixel_map.fill_rect(x, y, w, h, color, writer);
+                        METRICS.pixels_set_text.fetch_add(area, Relaxed);
                     }
-                    "SIZE" => {
+                    Command::Img {
+                        x,
+                        y,
+                        w,
+                        h,
+                        qoi_len,
+                    } => {
+                        // Always read the whole payload, so the stream stays in sync. The
+                        // buffer only grows as the bytes arrive.
+                        let len = qoi_len.unwrap_or(w * h * 4) as u64;
+                        let mut payload = Vec::new();
+                        match (&mut reader).take(len).read_to_end(&mut payload).await {
+                            Ok(read) if read as u64 == len => {}
+                            Ok(_) => break,
+                            Err(e) => {
+                                println!("Error: {}", e);
+                                break;
+                            }
+                        }
+                        let rgba = match qoi_len {
+                            Some(_) => match protocol::decode_qoi_image(&payload, w, h) {
+                                Ok(rgba) => rgba,
+                                Err(e) => {
+                                    if write_half
+                                        .write_all(format!("{}\n", e).as_bytes())
+                                        .await
+                                        .is_err()
+                                    {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            },
+                            None => payload,
+                        };
+                        if pixel_map.is_read_only() {
+                            if write_half.write_all(READ_ONLY).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        let (x, y) = (x.saturating_add(offset.0), y.saturating_add(offset.1));
+                        if x >= width || y >= height {
+                            if write_half
+                                .write_all("ERR: Out of Bounds (Tip: SIZE)\n".as_bytes())
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            continue;
+                        }
+                        let area = w.min(width - x) as u64 * h.min(height - y) as u64;
+                        match throttle.take(area, must_leave(&guard, &mut shutdown)).await {
+                            Some(true) => write_half.write_all(RATE_LIMITED).await.unwrap_or(()),
+                            Some(false) => {}
+                            // Unless the server is shutting down, which is handled first
+                            None => {
+                                kicked = true;
+                                break 'connection;
+                            }
+                        }
+                        if pixel_map.overlaps_protected(x, y, w, h)
+                            && write_half.write_all(PROTECTED).await.is_err()
+                        {
+                            break;
+                        }
+                        pixel_map.blit(x, y, w, h, &rgba, writer);
+                        METRICS.pixels_set_text.fetch_add(area, Relaxed);
+                    }
+                    Command::Size => {
                         let size = pixel_map.get_size();
-                        write_half
+                        if write_half
                             .write_all(format!("SIZE {} {}\n", size.0, size.1).as_bytes())
                             .await
-                            .unwrap();
+                            .is_err()
+                        {
+                            break;
+                        }
                     }
-                    "EXIT" => {
+                    Command::Offset { x, y } => {
+                        offset = (x, y);
+                    }
+                    Command::Canvas { name: Some(name) } => {
+                        let Some(next) = canvases.get(&name) else {
+                            let error =
+                                format!("ERR: Unknown Canvas (one of {})\n", canvases.names());
+                            if write_half.write_all(error.as_bytes()).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        };
+                        canvas = Arc::clone(next);
+                        size = canvas.pixel_map.watch_size();
+                        (width, height) = *size.borrow_and_update();
+                        let reply = format!("CANVAS {} {} {}\n", canvas.name, width, height);
+                        if write_half.write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Command::Canvas { name: None } => {
+                        let reply = format!("CANVAS {} {} {}\n", canvas.name, width, height);
+                        if write_half.write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Command::Who { x, y } => {
+                        if !pixel_map.is_attributed() {
+                            if write_half.write_all(NOT_ATTRIBUTED).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        let (px, py) = (x.saturating_add(offset.0), y.saturating_add(offset.1));
+                        if px >= width || py >= height {
+                            if write_half
+                                .write_all("ERR: Out of Bounds (Tip: SIZE)\n".as_bytes())
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            continue;
+                        }
+                        let reply = match pixel_map.last_write(px, py) {
+                            Some(write) => format!(
+                                "WHO {} {} {} {:08x} {}\n",
+                                x, y, write.writer.connection, write.writer.ip_hash, write.time
+                            ),
+                            None => format!("WHO {} {} none\n", x, y),
+                        };
+                        if write_half.write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Command::Exit => {
                         // exit program
-                        write_half.write_all("EXITING\n".as_bytes()).await.unwrap();
-                        write_half.flush().await.unwrap();
+                        let _ = write_half.write_all("EXITING\n".as_bytes()).await;
+                        let _ = write_half.flush().await;
                         return;
                     }
-                    "DEBUG" => {
+                    Command::Debug => {
                         debug = !debug;
                     }
-                    "BIN" => {
+                    Command::Bin => {
                         binary = !binary;
-                        write_half.write_all(b"\xac\xce\x91").await.unwrap();
-                    }
-                    "HELP" => {
-                        write_half
-                            .write_all("Commands:\nPX x y [hex]\nSIZE\nEXIT\nDEBUG\nBIN (changes channel mode: [x:u16][y:u16][rgba:u32] LE)\nHELP\n".as_bytes())
-                            .await
-                            .unwrap();
+                        if write_half.write_all(b"\xac\xce\x91").await.is_err() {
+                            break;
+                        }
                     }
-                    _ => {
-                        write_half
-                            .write_all("ERR: Unknown Command\n".as_bytes())
+                    Command::Help => {
+                        if write_half
+                            .write_all("Commands:\nPX x y [hex]\nRECT x y w h hex\nIMG x y w h [qoi length] (followed by w*h rgba bytes or a QOI image)\nSIZE\nOFFSET x y\nCANVAS [name]\nWHO x y\nEXIT\nDEBUG\nBIN (changes channel mode: [x:u16][y:u16][rgba:u32] LE)\nHELP\n".as_bytes())
                             .await
-                            .unwrap();
+                            .is_err()
+                        {
+                            break;
+                        }
                     }
                 }
             }
@@ -230,6 +778,24 @@ async fn handle_connection(mut socket: TcpStream, mut pixel_map: Arc<PixelMap>)
                 break;
             }
         }
-        write_half.flush().await.unwrap();
+        if write_half.flush().await.is_err() {
+            break;
+        }
+    }
+    if shutdown.is_triggered() {
+        let _ = write_half.write_all(b"EXITING: Server Shutting Down\n").await;
+        let _ = write_half.shutdown().await;
+    } else if kicked {
+        let _ = write_half.write_all(b"EXITING: Disconnected By Admin\n").await;
+        let _ = write_half.shutdown().await;
+    }
+}
+
+/// Completes once the client has to go, because an admin disconnected it or
+/// the server is shutting down
+async fn must_leave(guard: &ConnectionGuard, shutdown: &mut ShutdownSignal) {
+    tokio::select! {
+        _ = guard.kicked() => {}
+        _ = shutdown.recv() => {}
     }
 }
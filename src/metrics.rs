@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+/// Counters for the whole server, exposed in the Prometheus text format
+pub(crate) struct Metrics {
+    pub pixels_set_text: AtomicU64,
+    pub pixels_set_binary: AtomicU64,
+    pub pixels_read: AtomicU64,
+    pub tcp_connections: AtomicU64,
+    pub websocket_viewers: AtomicU64,
+    pub qoi_encodes: AtomicU64,
+    pub qoi_encode_micros: AtomicU64,
+    pub qoi_encoded_bytes: AtomicU64,
+    pub qoi_last_size: AtomicU64,
+    pub qoi_cache_hits: AtomicU64,
+    pub bytes_sent: AtomicU64,
+}
+
+pub(crate) static METRICS: Metrics = Metrics {
+    pixels_set_text: AtomicU64::new(0),
+    pixels_set_binary: AtomicU64::new(0),
+    pixels_read: AtomicU64::new(0),
+    tcp_connections: AtomicU64::new(0),
+    websocket_viewers: AtomicU64::new(0),
+    qoi_encodes: AtomicU64::new(0),
+    qoi_encode_micros: AtomicU64::new(0),
+    qoi_encoded_bytes: AtomicU64::new(0),
+    qoi_last_size: AtomicU64::new(0),
+    qoi_cache_hits: AtomicU64::new(0),
+    bytes_sent: AtomicU64::new(0),
+};
+
+/// Increments a gauge for as long as it is alive
+pub(crate) struct GaugeGuard(&'static AtomicU64);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static AtomicU64) -> Self {
+        gauge.fetch_add(1, Relaxed);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn record_encode(&self, duration: Duration, size: usize) {
+        self.qoi_encodes.fetch_add(1, Relaxed);
+        self.qoi_encode_micros
+            .fetch_add(duration.as_micros() as u64, Relaxed);
+        self.qoi_encoded_bytes.fetch_add(size as u64, Relaxed);
+        self.qoi_last_size.store(size as u64, Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+            let _ = writeln!(out, "# HELP pixelrust_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE pixelrust_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "pixelrust_{}{} {}", name, labels, value);
+            }
+        };
+        let get = |counter: &AtomicU64| counter.load(Relaxed) as f64;
+
+        metric(
+            "pixels_set_total",
+            "counter",
+            "Pixels set by pixelflut clients",
+            &[
+                ("{mode=\"text\"}", get(&self.pixels_set_text)),
+                ("{mode=\"binary\"}", get(&self.pixels_set_binary)),
+            ],
+        );
+        metric(
+            "pixels_read_total",
+            "counter",
+            "Pixels read by pixelflut clients",
+            &[("", get(&self.pixels_read))],
+        );
+        metric(
+            "tcp_connections",
+            "gauge",
+            "Open pixelflut connections",
+            &[("", get(&self.tcp_connections))],
+        );
+        metric(
+            "websocket_viewers",
+            "gauge",
+            "Connected WebSocket viewers",
+            &[("", get(&self.websocket_viewers))],
+        );
+        metric(
+            "qoi_encodes_total",
+            "counter",
+            "Full canvas QOI encodes",
+            &[("", get(&self.qoi_encodes))],
+        );
+        metric(
+            "qoi_encode_seconds_total",
+            "counter",
+            "Time spent encoding the canvas as QOI",
+            &[("", get(&self.qoi_encode_micros) / 1e6)],
+        );
+        metric(
+            "qoi_encoded_bytes_total",
+            "counter",
+            "Bytes of QOI produced by canvas encodes",
+            &[("", get(&self.qoi_encoded_bytes))],
+        );
+        metric(
+            "qoi_size_bytes",
+            "gauge",
+            "Size of the last QOI encoded canvas",
+            &[("", get(&self.qoi_last_size))],
+        );
+        metric(
+            "qoi_cache_hits_total",
+            "counter",
+            "Canvas requests answered from the QOI cache",
+            &[("", get(&self.qoi_cache_hits))],
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Bytes of canvas data sent to HTTP and WebSocket clients",
+            &[("", get(&self.bytes_sent))],
+        );
+        out
+    }
+}
@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::canvases::{self, MAIN_CANVAS};
+use crate::color::Color;
+use crate::image::{self, Fit};
+
+/// A protected region from the `protect` option, written as
+/// `[canvas:]WIDTHxHEIGHT+X+Y[=IMAGE]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct RegionSpec {
+    pub canvas: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub image: Option<String>,
+}
+
+impl FromStr for RegionSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (rest, image) = match spec.split_once('=') {
+            Some((rest, image)) if !image.is_empty() => (rest, Some(image.to_string())),
+            Some(_) => return Err(format!("Missing image in region '{}'", spec)),
+            None => (spec, None),
+        };
+        let (canvas, geometry) = match rest.split_once(':') {
+            Some((canvas, geometry)) if canvases::is_valid_name(canvas) => (canvas, geometry),
+            Some((canvas, _)) => return Err(format!("Invalid canvas name '{}'", canvas)),
+            None => (MAIN_CANVAS, rest),
+        };
+        let invalid = || format!("Invalid region '{}' (WIDTHxHEIGHT+X+Y)", geometry);
+        let mut parts = geometry.split('+');
+        let (size, x, y) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(size), Some(x), Some(y), None) => (size, x, y),
+            _ => return Err(invalid()),
+        };
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let number = |value: &str| value.parse::<u32>().map_err(|_| invalid());
+        let (width, height) = (number(width)?, number(height)?);
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        Ok(RegionSpec {
+            canvas: canvas.to_string(),
+            x: number(x)?,
+            y: number(y)?,
+            width,
+            height,
+            image,
+        })
+    }
+}
+
+impl TryFrom<String> for RegionSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        spec.parse()
+    }
+}
+
+/// A rectangle of the canvas that clients can't draw on. Its image, if it has
+/// one, is drawn on top of the canvas whenever the canvas is rendered.
+pub(crate) struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    image: Option<Vec<Color>>,
+}
+
+impl Region {
+    /// Reads the image of the region, which is stretched to fill it
+    pub fn load(spec: &RegionSpec) -> Result<Region, String> {
+        let image = match &spec.image {
+            Some(path) => {
+                let data = std::fs::read(path)
+                    .map_err(|e| format!("Could not read {}: {}", path, e))?;
+                let image = image::decode(&data)
+                    .map_err(|e| format!("Could not load {}: {}", path, e))?;
+                let image = Fit::Stretch.apply(image, (spec.width, spec.height));
+                let pixels = image
+                    .rgba
+                    .chunks_exact(4)
+                    .map(|p| Color::from_rgba(p[0], p[1], p[2], p[3]))
+                    .collect();
+                Some(pixels)
+            }
+            None => None,
+        };
+        Ok(Region {
+            x: spec.x,
+            y: spec.y,
+            width: spec.width,
+            height: spec.height,
+            image,
+        })
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x.wrapping_sub(self.x) < self.width && y.wrapping_sub(self.y) < self.height
+    }
+
+    /// Whether the region shares a pixel with the `w`x`h` rectangle at (x, y)
+    pub fn overlaps(&self, x: u32, y: u32, w: u32, h: u32) -> bool {
+        x < self.x.saturating_add(self.width)
+            && self.x < x.saturating_add(w)
+            && y < self.y.saturating_add(self.height)
+            && self.y < y.saturating_add(h)
+    }
+
+    pub fn has_image(&self) -> bool {
+        self.image.is_some()
+    }
+
+    /// Draws the image of the region over `color`, the pixel at (x, y)
+    pub fn cover(&self, x: u32, y: u32, color: &mut Color) {
+        if let Some(image) = &self.image {
+            if self.contains(x, y) {
+                let i = (x - self.x) as usize + (y - self.y) as usize * self.width as usize;
+                color.overlay_mut(image[i]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "200x100+10+20".parse(),
+            Ok(RegionSpec {
+                canvas: MAIN_CANVAS.to_string(),
+                x: 10,
+                y: 20,
+                width: 200,
+                height: 100,
+                image: None
+            })
+        );
+        let spec: RegionSpec = "sandbox:5x5+0+0=logos/a.png".parse().unwrap();
+        assert_eq!(spec.canvas, "sandbox");
+        assert_eq!(spec.image.as_deref(), Some("logos/a.png"));
+        assert!("0x5+0+0".parse::<RegionSpec>().is_err());
+        assert!("5x5+0".parse::<RegionSpec>().is_err());
+        assert!("5x5+0+0=".parse::<RegionSpec>().is_err());
+        assert!("Main:5x5+0+0".parse::<RegionSpec>().is_err());
+    }
+
+    #[test]
+    fn covers_only_its_rectangle() {
+        let region = Region {
+            x: 2,
+            y: 2,
+            width: 2,
+            height: 1,
+            image: Some(vec![Color::from_rgb(255, 0, 0), Color::from_rgba(0, 0, 255, 0)]),
+        };
+        assert!(region.contains(3, 2));
+        assert!(!region.contains(4, 2) && !region.contains(1, 2) && !region.contains(2, 3));
+        assert!(region.overlaps(0, 0, 3, 3));
+        assert!(!region.overlaps(0, 0, 2, 2));
+        assert!(!region.overlaps(4, 0, u32::MAX, u32::MAX));
+
+        let mut color = Color::black();
+        region.cover(2, 2, &mut color);
+        assert_eq!(color, Color::from_rgb(255, 0, 0));
+        // Transparent parts of the image show the canvas
+        let mut color = Color::black();
+        region.cover(3, 2, &mut color);
+        assert_eq!(color, Color::black());
+    }
+}
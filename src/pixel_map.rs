@@ -1,130 +1,427 @@
+use crate::attribution::{self, Attribution, LastWrite, Writer};
 use crate::color::Color;
+use crate::image::{self, Fit, Image};
+use crate::metrics::METRICS;
+use crate::overlay::Region;
 use rapid_qoi::Colors;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
-use std::sync::atomic::{AtomicU32, AtomicUsize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
 use std::sync::{Arc, RwLock};
-use tokio::runtime::Handle;
+use std::time::Instant;
+use tokio::sync::watch;
+
+/// Edge length of the square tiles used for change tracking
+pub const TILE_SIZE: u32 = 32;
+
+/// Marker byte at the start of an encoded delta frame
+const DELTA_MARKER: u8 = b'D';
+
+/// An encoded QOI image of the canvas, shared with every viewer
+pub type EncodedQoi = Arc<Box<[u8]>>;
 
 pub(crate) struct PixelMap {
-    pixels: Vec<AtomicU32>,
-    width: AtomicU32,
-    height: AtomicU32,
+    // Swapped out as a whole when the canvas is resized
+    canvas: RwLock<Canvas>,
     version: AtomicUsize,
-    cache: RwLock<Arc<Box<[u8]>>>,
+    // Encoded QOI together with the version it was encoded at
+    cache: RwLock<(usize, EncodedQoi)>,
+    generation: AtomicUsize,
+    // Colorspace written into QOI headers, kept from the image the canvas was loaded from
+    colors: Colors,
+    // Current size, for pixelflut clients to pick up resizes
+    size: watch::Sender<(u32, u32)>,
+    // Protected regions, their images are drawn on top when rendering
+    overlay: Vec<Region>,
+    // Set by admins to stop clients from drawing
+    read_only: AtomicBool,
+    // Whether the canvas keeps track of who drew each pixel
+    attributed: bool,
+}
+
+struct Canvas {
+    pixels: Vec<AtomicU32>,
+    width: u32,
+    height: u32,
+    // Generation at which each tile was last written to. A viewer that has
+    // seen everything up to generation `n` only needs the tiles stamped `>= n`.
+    tiles: Vec<AtomicUsize>,
+    tiles_x: u32,
+    attribution: Option<Attribution>,
+}
+
+impl Canvas {
+    fn new(
+        pixels: Vec<AtomicU32>,
+        width: u32,
+        height: u32,
+        generation: usize,
+        attributed: bool,
+    ) -> Canvas {
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        Canvas {
+            attribution: attributed.then(|| Attribution::new(pixels.len())),
+            pixels,
+            width,
+            height,
+            tiles: (0..tiles_x * tiles_y)
+                .map(|_| AtomicUsize::new(generation))
+                .collect(),
+            tiles_x,
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> Option<Color> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let i = x as usize + y as usize * self.width as usize;
+        Some(Color::new(self.pixels[i].load(Relaxed)))
+    }
+
+    fn last_write(&self, x: u32, y: u32) -> Option<LastWrite> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let i = x as usize + y as usize * self.width as usize;
+        self.attribution.as_ref()?.get(i)
+    }
+}
+
+/// What a viewer needs to catch up with the canvas
+pub enum CanvasUpdate {
+    Unchanged,
+    Full(EncodedQoi),
+    Delta(Vec<u8>),
 }
 
 impl PixelMap {
     pub fn new(width: u32, height: u32) -> PixelMap {
-        let mut pixels = Vec::with_capacity((width * height) as usize);
-        for _ in 0..width {
-            for _ in 0..height {
-                pixels.push(AtomicU32::new(Color::black().raw()));
-            }
-        }
+        let pixels = (0..width as usize * height as usize)
+            .map(|_| AtomicU32::new(Color::black().raw()))
+            .collect();
+        PixelMap::from_pixels(pixels, width, height, Colors::Rgba)
+    }
+
+    fn from_pixels(pixels: Vec<AtomicU32>, width: u32, height: u32, colors: Colors) -> PixelMap {
         PixelMap {
-            pixels,
-            width: AtomicU32::new(width),
-            height: AtomicU32::new(height),
+            canvas: RwLock::new(Canvas::new(pixels, width, height, 0, false)),
             version: AtomicUsize::new(1),
-            cache: RwLock::new(Arc::new(Box::new([0]))),
+            cache: RwLock::new((0, Arc::new(Box::new([0])))),
+            generation: AtomicUsize::new(1),
+            colors,
+            size: watch::Sender::new((width, height)),
+            overlay: Vec::new(),
+            read_only: AtomicBool::new(false),
+            attributed: false,
         }
     }
 
-    pub fn load_image(filename: &str) -> PixelMap {
-        let img = match std::fs::read(filename) {
-            Ok(x) => x,
-            Err(_) => return PixelMap::new(1280, 720),
-        };
-        let qoi = match rapid_qoi::Qoi::decode_alloc(&img) {
-            Ok(x) => x,
-            Err(_) => return PixelMap::new(1280, 720),
-        };
-        let (width, height) = (qoi.0.width, qoi.0.height);
-        let mut pixels = Vec::new();
-        for y in 0..height {
-            for x in 0..width {
-                let color = match qoi.0.colors {
-                    Colors::Rgb => Color::from_rgb(
-                        qoi.1[(x + y * width) as usize * 3],
-                        qoi.1[(x + y * width) as usize * 3 + 1],
-                        qoi.1[(x + y * width) as usize * 3 + 2],
-                    ),
-                    Colors::Rgba => Color::from_rgba(
-                        qoi.1[(x + y * width) as usize * 4],
-                        qoi.1[(x + y * width) as usize * 4 + 1],
-                        qoi.1[(x + y * width) as usize * 4 + 2],
-                        qoi.1[(x + y * width) as usize * 4 + 3],
-                    ),
-                    _ => Color::black(),
-                };
-                pixels.push(AtomicU32::new(color.raw()));
-            }
+    /// Starts keeping track of who draws each pixel
+    pub fn enable_attribution(&mut self) {
+        self.attributed = true;
+        let canvas = self.canvas.get_mut().unwrap();
+        canvas.attribution = Some(Attribution::new(canvas.pixels.len()));
+    }
+
+    pub fn is_attributed(&self) -> bool {
+        self.attributed
+    }
+
+    /// Who last drew the pixel at (x, y) and when. None if nobody did since
+    /// the server started, or the canvas isn't attributed.
+    pub fn last_write(&self, x: u32, y: u32) -> Option<LastWrite> {
+        self.canvas.read().unwrap().last_write(x, y)
+    }
+
+    /// Protects `regions` from clients and draws their images on top of the canvas
+    pub fn set_overlay(&mut self, regions: Vec<Region>) {
+        self.overlay = regions;
+    }
+
+    /// Whether (x, y) is in a protected region
+    pub fn is_protected(&self, x: u32, y: u32) -> bool {
+        self.overlay.iter().any(|region| region.contains(x, y))
+    }
+
+    /// Whether the `w`x`h` rectangle at (x, y) touches a protected region
+    pub fn overlaps_protected(&self, x: u32, y: u32, w: u32, h: u32) -> bool {
+        self.overlay.iter().any(|region| region.overlaps(x, y, w, h))
+    }
+
+    /// Whether clients are currently kept from drawing
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Relaxed);
+    }
+
+    /// `color` at (x, y) with the overlay images drawn on top
+    fn composite(&self, x: u32, y: u32, mut color: Color) -> Color {
+        for region in &self.overlay {
+            region.cover(x, y, &mut color);
         }
-        PixelMap {
-            pixels,
-            width: AtomicU32::new(width),
-            height: AtomicU32::new(height),
-            version: AtomicUsize::new(1),
-            cache: RwLock::new(Arc::new(Box::new([0]))),
+        color
+    }
+
+    /// Loads the canvas from a QOI, PNG or PPM image. An image of another
+    /// size than `width`x`height` is fitted into it according to `fit`.
+    pub fn load_image(
+        filename: &str,
+        width: u32,
+        height: u32,
+        fit: Fit,
+    ) -> Result<PixelMap, String> {
+        let data = std::fs::read(filename)
+            .map_err(|e| format!("Could not read {}: {}", filename, e))?;
+        let image = image::decode(&data)
+            .map_err(|e| format!("Could not load {}: {}", filename, e))?;
+        let Image {
+            width,
+            height,
+            rgba,
+            srgb,
+        } = fit.apply(image, (width, height));
+        // Binary mode addresses pixels with u16 coordinates
+        let max = u16::MAX as u32 + 1;
+        if width == 0 || height == 0 || width > max || height > max {
+            return Err(format!(
+                "{} is {}x{}, the canvas must be between 1x1 and 65536x65536",
+                filename, width, height
+            ));
         }
+        let pixels = rgba
+            .chunks_exact(4)
+            .map(|p| AtomicU32::new(Color::from_rgba(p[0], p[1], p[2], p[3]).raw()))
+            .collect();
+        let colors = match srgb {
+            true => Colors::SrgbLinA,
+            false => Colors::Rgba,
+        };
+        Ok(PixelMap::from_pixels(pixels, width, height, colors))
     }
 
-    pub fn get_color(&self, x: u32, y: u32) -> Color {
-        Color::new(self.pixels[(x + y * self.width.load(Relaxed)) as usize].load(Relaxed))
+    /// Color of the pixel at (x, y) as shown to viewers, None if it is
+    /// outside of the canvas
+    pub fn get_color(&self, x: u32, y: u32) -> Option<Color> {
+        let color = self.canvas.read().unwrap().get(x, y)?;
+        Some(self.composite(x, y, color))
     }
 
-    pub fn get_pixel(&self, x: u32, y: u32) -> &AtomicU32 {
+    /// Blends `color` onto the pixel at (x, y)
+    pub fn blend_color(&self, x: u32, y: u32, color: Color, writer: Writer) {
+        self.blend_pixels([(x, y, color)], writer);
+    }
+
+    /// Blends a batch of pixels onto the canvas. The version and change
+    /// tracking are only updated once for the whole batch. Pixels outside of
+    /// the canvas are skipped, the canvas may have shrunk since they were
+    /// checked, and so are protected pixels. Every write is attributed to
+    /// `writer` if the canvas is attributed.
+    pub fn blend_pixels(
+        &self,
+        pixels: impl IntoIterator<Item = (u32, u32, Color)>,
+        writer: Writer,
+    ) {
+        let canvas = self.canvas.read().unwrap();
+        let dirty = self.store_pixels(&canvas, pixels, writer);
+        if dirty.is_empty() {
+            return;
+        }
         self.version.fetch_add(1, SeqCst);
-        &self.pixels[(x + y * self.width.load(Relaxed)) as usize]
+        // Stamp after the stores, so a frame taken in between still picks the
+        // tiles up the next time around
+        stamp_tiles(&canvas, &dirty, self.generation.load(SeqCst));
+    }
+
+    /// Writes the pixels and returns the tiles that changed
+    fn store_pixels(
+        &self,
+        canvas: &Canvas,
+        pixels: impl IntoIterator<Item = (u32, u32, Color)>,
+        writer: Writer,
+    ) -> Vec<u32> {
+        let time = attribution::now();
+        let mut dirty: Vec<u32> = Vec::new();
+        for (x, y, color) in pixels {
+            if x >= canvas.width || y >= canvas.height || self.is_protected(x, y) {
+                continue;
+            }
+            let i = x as usize + y as usize * canvas.width as usize;
+            if let Some(attribution) = &canvas.attribution {
+                attribution.record(i, writer, time);
+            }
+            let pixel = &canvas.pixels[i];
+            let original_color = Color::new(pixel.load(Relaxed));
+            let mut new_color = original_color;
+            new_color.overlay_mut(color);
+            if new_color.equals(original_color) {
+                continue;
+            }
+            pixel.store(new_color.raw(), Relaxed);
+            let tile = (x / TILE_SIZE) + (y / TILE_SIZE) * canvas.tiles_x;
+            if dirty.last() != Some(&tile) {
+                dirty.push(tile);
+            }
+        }
+        dirty
     }
 
-    pub fn get_width(&self) -> u32 {
-        self.width.load(Relaxed)
+    /// Blends `color` onto the rectangle, cut off at the canvas border
+    pub fn fill_rect(&self, x: u32, y: u32, w: u32, h: u32, color: Color, writer: Writer) {
+        let (width, height) = self.get_size();
+        let x_end = x.saturating_add(w).min(width);
+        let y_end = y.saturating_add(h).min(height);
+        let pixels = (y..y_end).flat_map(|py| (x..x_end).map(move |px| (px, py, color)));
+        self.blend_pixels(pixels, writer);
     }
 
-    pub fn get_height(&self) -> u32 {
-        self.height.load(Relaxed)
+    /// Blends a `w`x`h` image of rgba pixels onto the canvas with its top left
+    /// corner at (x, y). Everything outside the canvas is cut off.
+    pub fn blit(&self, x: u32, y: u32, w: u32, h: u32, rgba: &[u8], writer: Writer) {
+        let (width, height) = self.get_size();
+        let pixels = rgba
+            .chunks_exact(4)
+            .take((w * h) as usize)
+            .enumerate()
+            .map(|(i, p)| {
+                let (px, py) = (x + i as u32 % w, y + i as u32 / w);
+                (px, py, Color::from_rgba(p[0], p[1], p[2], p[3]))
+            })
+            .filter(|&(px, py, _)| px < width && py < height);
+        self.blend_pixels(pixels, writer);
     }
 
     pub fn get_size(&self) -> (u32, u32) {
-        (self.get_width(), self.get_height())
+        let canvas = self.canvas.read().unwrap();
+        (canvas.width, canvas.height)
     }
 
-    pub fn to_qoi(&self, tokio_handle: Arc<Handle>) -> (Arc<Box<[u8]>>, bool) {
-        if self.version.load(SeqCst) == 0 {
-            match self.cache.read() {
-                Ok(cache) => {
-                    return (cache.clone(), true);
-                }
-                Err(_) => {
-                    println!("Failed to get the read-lock for the cache, will just try generating a new one...")
+    /// Subscribes to the size of the canvas, which changes on [`PixelMap::resize`]
+    pub fn watch_size(&self) -> watch::Receiver<(u32, u32)> {
+        self.size.subscribe()
+    }
+
+    /// Resizes the canvas to `width`x`height`. The top left of the current
+    /// canvas is kept, new pixels are filled with `fill`. Viewers get a full
+    /// frame with the next update.
+    pub fn resize(&self, width: u32, height: u32, fill: Color) {
+        self.replace(width, height, true, |canvas, x, y| {
+            canvas.get(x, y).unwrap_or(fill)
+        });
+    }
+
+    /// Sets every pixel of the canvas to `color`, protected regions included
+    pub fn fill(&self, color: Color) {
+        let (width, height) = self.get_size();
+        self.replace(width, height, false, |_, _, _| color);
+    }
+
+    /// Replaces the canvas with `image`, taking over its size
+    pub fn load(&self, image: &Image) {
+        self.replace(image.width, image.height, false, |_, x, y| {
+            let i = (x as usize + y as usize * image.width as usize) * 4;
+            let p = &image.rgba[i..i + 4];
+            Color::from_rgba(p[0], p[1], p[2], p[3])
+        });
+    }
+
+    /// Swaps in a new `width`x`height` canvas, `pixel` gives the color of
+    /// each pixel from the current canvas. Pixels at the same position keep
+    /// their attribution with `keep_attribution`. Viewers get a full frame
+    /// with the next update, clients are told if the size changed.
+    fn replace(
+        &self,
+        width: u32,
+        height: u32,
+        keep_attribution: bool,
+        pixel: impl Fn(&Canvas, u32, u32) -> Color,
+    ) {
+        {
+            let mut canvas = self.canvas.write().unwrap();
+            let pixels = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| AtomicU32::new(pixel(&canvas, x, y).raw()))
+                .collect();
+            // Every tile counts as changed
+            let generation = self.generation.load(SeqCst);
+            let replaced = Canvas::new(pixels, width, height, generation, self.attributed);
+            if let Some(attribution) = replaced.attribution.as_ref().filter(|_| keep_attribution) {
+                for (x, y) in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))) {
+                    if let Some(write) = canvas.last_write(x, y) {
+                        let i = x as usize + y as usize * width as usize;
+                        attribution.record(i, write.writer, write.time);
+                    }
                 }
-            };
+            }
+            *canvas = replaced;
+            self.version.fetch_add(1, SeqCst);
         }
-        let w = self.get_width();
-        let h = self.get_height();
-        let mut buf = Vec::with_capacity((w * h * 4) as usize);
-        self.pixels
+        self.size.send_if_modified(|size| {
+            let changed = *size != (width, height);
+            *size = (width, height);
+            changed
+        });
+    }
+
+    /// Counter that increases with every write to the canvas
+    pub fn version(&self) -> usize {
+        self.version.load(SeqCst)
+    }
+
+    /// Current change tracking generation, see [`PixelMap::update_since`]
+    pub fn generation(&self) -> usize {
+        self.generation.load(SeqCst)
+    }
+
+    /// Copies the canvas as rgba bytes, row by row, as shown to viewers
+    pub fn to_image(&self) -> Image {
+        self.copy_image(true)
+    }
+
+    fn copy_image(&self, with_overlay: bool) -> Image {
+        let canvas = self.canvas.read().unwrap();
+        let mut rgba = Vec::with_capacity(canvas.pixels.len() * 4);
+        canvas
+            .pixels
             .iter()
-            .for_each(|x| Color::new(x.load(Relaxed)).add_to_vec(&mut buf));
-        let qoi = rapid_qoi::Qoi {
-            width: w,
-            height: h,
-            colors: Colors::Rgba,
-        };
-        let qoi_buffer = qoi.encode_alloc(&buf).unwrap();
+            .for_each(|x| Color::new(x.load(Relaxed)).add_to_vec(&mut rgba));
+        if with_overlay && self.has_overlay_images() {
+            for (i, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+                let (x, y) = (i as u32 % canvas.width, i as u32 / canvas.width);
+                let color = Color::from_rgba(pixel[0], pixel[1], pixel[2], pixel[3]);
+                let color = self.composite(x, y, color);
+                pixel.copy_from_slice(&[color.r(), color.g(), color.b(), color.a()]);
+            }
+        }
+        Image {
+            width: canvas.width,
+            height: canvas.height,
+            rgba,
+            srgb: matches!(self.colors, Colors::SrgbLinA),
+        }
+    }
 
-        let qoi_arc = Arc::new(qoi_buffer.into_boxed_slice());
-        let t_arc = Arc::clone(&qoi_arc);
-        tokio_handle.spawn(async move {
-            tokio::fs::write("image.qoi", &*t_arc).await.unwrap();
-        });
+    pub fn to_qoi(&self) -> Result<(EncodedQoi, bool), String> {
+        let version = self.version.load(SeqCst);
+        match self.cache.read() {
+            Ok(cache) if cache.0 == version => {
+                METRICS.qoi_cache_hits.fetch_add(1, Relaxed);
+                return Ok((cache.1.clone(), true));
+            }
+            Ok(_) => {}
+            Err(_) => {
+                println!("Failed to get the read-lock for the cache, will just try generating a new one...")
+            }
+        };
+        let qoi_arc = Arc::new(self.encode_qoi(self.to_image())?.into_boxed_slice());
 
         {
             match self.cache.write() {
                 Ok(mut write) => {
-                    *write = qoi_arc.clone();
-                    self.version.store(0, SeqCst);
+                    *write = (version, qoi_arc.clone());
                 }
                 Err(_) => {
                     println!("Failed to get write lock for the cache...")
@@ -132,8 +429,119 @@ impl PixelMap {
             }
         }
 
-        (qoi_arc, false)
+        Ok((qoi_arc, false))
+    }
+
+    /// The canvas without the overlay images, for snapshots. They would stay on
+    /// the canvas otherwise, even once their regions are gone.
+    pub fn to_base_qoi(&self) -> Result<EncodedQoi, String> {
+        match self.has_overlay_images() {
+            true => Ok(Arc::new(self.encode_qoi(self.copy_image(false))?.into_boxed_slice())),
+            false => Ok(self.to_qoi()?.0),
+        }
+    }
+
+    fn has_overlay_images(&self) -> bool {
+        self.overlay.iter().any(Region::has_image)
+    }
+
+    fn encode_qoi(&self, image: Image) -> Result<Vec<u8>, String> {
+        let start = Instant::now();
+        let qoi = rapid_qoi::Qoi {
+            width: image.width,
+            height: image.height,
+            colors: self.colors,
+        };
+        let qoi_buffer = qoi
+            .encode_alloc(&image.rgba)
+            .map_err(|e| format!("Failed to encode the canvas: {}", e))?;
+        METRICS.record_encode(start.elapsed(), qoi_buffer.len());
+        Ok(qoi_buffer)
+    }
+
+    /// Returns the changes since the generation in `seen` and advances it.
+    /// Start with `seen` at 0 to get a full frame. Falls back to a full frame
+    /// if more than half of the tiles changed, as that is cheaper to encode.
+    pub fn update_since(&self, seen: &mut usize) -> CanvasUpdate {
+        let since = *seen;
+        // The next scan includes the generation that ends here. A writer may
+        // have read it just before and stamp its tiles only after this scan.
+        *seen = self.generation.fetch_add(1, SeqCst);
+
+        {
+            let canvas = self.canvas.read().unwrap();
+            let dirty: Vec<u32> = (0..canvas.tiles.len() as u32)
+                .filter(|&i| canvas.tiles[i as usize].load(SeqCst) >= since)
+                .collect();
+            if dirty.is_empty() {
+                return CanvasUpdate::Unchanged;
+            }
+            if dirty.len() * 2 <= canvas.tiles.len() {
+                return CanvasUpdate::Delta(encode_tiles(
+                    canvas.width,
+                    canvas.height,
+                    &dirty,
+                    |x, y, buf| self.composite(x, y, canvas.get(x, y).unwrap()).add_to_vec(buf),
+                ));
+            }
+        }
+        match self.to_qoi() {
+            Ok((qoi, _)) => CanvasUpdate::Full(qoi),
+            Err(e) => {
+                println!("{}", e);
+                CanvasUpdate::Unchanged
+            }
+        }
+    }
+}
+
+/// Marks the tiles as changed in `generation`
+fn stamp_tiles(canvas: &Canvas, tiles: &[u32], generation: usize) {
+    for &tile in tiles {
+        let stamp = &canvas.tiles[tile as usize];
+        if stamp.load(Relaxed) < generation {
+            stamp.fetch_max(generation, SeqCst);
+        }
+    }
+}
+
+/* Delta Frame
+// Format:
+// [u8: 'D'][u32: tile count]
+// then per tile:
+// [u16: x][u16: y][u16: w][u16: h][w * h * rgba: u8]
+//
+// All integers are little endian. Tiles at the right and bottom edge are
+// cut off at the canvas border, so w and h can be smaller than TILE_SIZE.
+ */
+/// Encodes `tiles` of a `width`x`height` canvas as a delta frame. Tiles are
+/// numbered row by row, `pixel` appends the rgba bytes of the pixel at (x, y).
+pub fn encode_tiles(
+    width: u32,
+    height: u32,
+    tiles: &[u32],
+    mut pixel: impl FnMut(u32, u32, &mut Vec<u8>),
+) -> Vec<u8> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let area = (TILE_SIZE * TILE_SIZE * 4) as usize;
+    let mut buf = Vec::with_capacity(5 + tiles.len() * (8 + area));
+    buf.push(DELTA_MARKER);
+    buf.extend_from_slice(&(tiles.len() as u32).to_le_bytes());
+    for &tile in tiles {
+        let x = (tile % tiles_x) * TILE_SIZE;
+        let y = (tile / tiles_x) * TILE_SIZE;
+        let w = TILE_SIZE.min(width - x);
+        let h = TILE_SIZE.min(height - y);
+        for value in [x, y, w, h] {
+            buf.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        for row in y..y + h {
+            for col in x..x + w {
+                pixel(col, row, &mut buf);
+            }
+        }
     }
+    buf
 }
 
 // Why did I even have a Clone implementation??? I'm passing around Arcs
@@ -156,3 +564,213 @@ impl PixelMap {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rapid_qoi::Qoi;
+
+    /// Writes a 2x1 QOI image and loads it as a canvas
+    fn load_qoi(colors: Colors, pixels: &[u8]) -> PixelMap {
+        let qoi = Qoi {
+            width: 2,
+            height: 1,
+            colors,
+        }
+        .encode_alloc(pixels)
+        .unwrap();
+        let name = format!("pixelrust-{}-{:?}.qoi", std::process::id(), colors);
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, qoi).unwrap();
+        let pixel_map = PixelMap::load_image(path.to_str().unwrap(), 1, 1, Fit::Keep);
+        std::fs::remove_file(&path).unwrap();
+        pixel_map.unwrap()
+    }
+
+    #[test]
+    fn qoi_colorspaces_round_trip() {
+        let rgb = [1, 2, 3, 4, 5, 6];
+        let rgba = [1, 2, 3, 255, 4, 5, 6, 128];
+        for (colors, pixels, srgb) in [
+            (Colors::Rgb, &rgb[..], false),
+            (Colors::Srgb, &rgb[..], true),
+            (Colors::Rgba, &rgba[..], false),
+            (Colors::SrgbLinA, &rgba[..], true),
+        ] {
+            let pixel_map = load_qoi(colors, pixels);
+            let expected = match colors.has_alpha() {
+                true => rgba.to_vec(),
+                false => vec![1, 2, 3, 255, 4, 5, 6, 255],
+            };
+            assert_eq!(pixel_map.to_image().rgba, expected, "{:?}", colors);
+
+            // The canvas always has alpha, but keeps the colorspace
+            let saved = pixel_map.to_qoi().unwrap().0;
+            assert_eq!(saved[12], 4, "{:?}", colors);
+            assert_eq!(saved[13], if srgb { 0 } else { 1 }, "{:?}", colors);
+            let (qoi, pixels) = Qoi::decode_alloc(&saved).unwrap();
+            assert_eq!(matches!(qoi.colors, Colors::SrgbLinA), srgb, "{:?}", colors);
+            assert_eq!(pixels, expected, "{:?}", colors);
+        }
+    }
+
+    #[test]
+    fn resize_keeps_the_top_left() {
+        let pixel_map = PixelMap::new(2, 2);
+        let white = Color::from_rgb(255, 255, 255);
+        let red = Color::from_rgb(255, 0, 0);
+        pixel_map.blend_color(1, 1, white, Writer::default());
+        let mut size = pixel_map.watch_size();
+        let mut seen = 0;
+        pixel_map.update_since(&mut seen);
+
+        pixel_map.resize(3, 1, red);
+        assert_eq!(*size.borrow_and_update(), (3, 1));
+        assert_eq!(pixel_map.get_size(), (3, 1));
+        assert_eq!(pixel_map.get_color(2, 0).map(|c| c.raw()), Some(red.raw()));
+        assert!(pixel_map.get_color(1, 1).is_none());
+        // Viewers start over with the new size
+        assert!(matches!(pixel_map.update_since(&mut seen), CanvasUpdate::Full(_)));
+
+        pixel_map.resize(2, 2, red);
+        assert_eq!(pixel_map.get_color(1, 1).map(|c| c.raw()), Some(red.raw()));
+        assert_eq!(pixel_map.get_color(0, 0).map(|c| c.raw()), Some(Color::black().raw()));
+        // Writes outside of a shrunk canvas are dropped
+        pixel_map.blend_pixels([(5, 5, white)], Writer::default());
+    }
+
+    /// Applies an update to `mirror`, the rgba bytes of a `width` wide canvas
+    fn apply_update(update: CanvasUpdate, width: u32, mirror: &mut [u8]) {
+        let delta = match update {
+            CanvasUpdate::Unchanged => return,
+            CanvasUpdate::Full(qoi) => {
+                mirror.copy_from_slice(&Qoi::decode_alloc(&qoi).unwrap().1);
+                return;
+            }
+            CanvasUpdate::Delta(delta) => delta,
+        };
+        let u16_at = |i: usize| u16::from_le_bytes([delta[i], delta[i + 1]]) as usize;
+        let mut i = 5;
+        while i < delta.len() {
+            let (x, y, w, h) = (u16_at(i), u16_at(i + 2), u16_at(i + 4), u16_at(i + 6));
+            i += 8;
+            for row in y..y + h {
+                let start = (x + row * width as usize) * 4;
+                mirror[start..start + w * 4].copy_from_slice(&delta[i..i + w * 4]);
+                i += w * 4;
+            }
+        }
+    }
+
+    #[test]
+    fn late_stamps_are_picked_up() {
+        let pixel_map = PixelMap::new(TILE_SIZE * 2, TILE_SIZE);
+        let mut seen = 0;
+        pixel_map.update_since(&mut seen);
+        // A writer reads the generation right before a frame is taken and
+        // stamps its tile only after the frame
+        let white = Color::from_rgb(255, 255, 255);
+        let canvas = pixel_map.canvas.read().unwrap();
+        let dirty = pixel_map.store_pixels(&canvas, [(0, 0, white)], Writer::default());
+        let generation = pixel_map.generation();
+        drop(canvas);
+        assert!(matches!(pixel_map.update_since(&mut seen), CanvasUpdate::Unchanged));
+        stamp_tiles(&pixel_map.canvas.read().unwrap(), &dirty, generation);
+        let mut mirror = vec![0; (TILE_SIZE * TILE_SIZE * 8) as usize];
+        apply_update(pixel_map.update_since(&mut seen), TILE_SIZE * 2, &mut mirror);
+        assert_eq!(mirror[..4], [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn updates_miss_no_concurrent_writes() {
+        let (width, height) = (8 * TILE_SIZE, 8 * TILE_SIZE);
+        let pixel_map = Arc::new(PixelMap::new(width, height));
+        let mut mirror = pixel_map.to_image().rgba;
+        let mut seen = 0;
+        apply_update(pixel_map.update_since(&mut seen), width, &mut mirror);
+
+        let writers: Vec<_> = (0..4u32)
+            .map(|n| {
+                let pixel_map = Arc::clone(&pixel_map);
+                std::thread::spawn(move || {
+                    for i in 0..2_000u32 {
+                        // One pixel on every tile, stamped in one go after all stores
+                        let color = Color::from_rgb(n as u8, (i >> 8) as u8, i as u8);
+                        let pixels = (0..width / TILE_SIZE * height / TILE_SIZE).map(|tile| {
+                            let x = tile % (width / TILE_SIZE) * TILE_SIZE + i % TILE_SIZE;
+                            let y = tile / (width / TILE_SIZE) * TILE_SIZE + n;
+                            (x, y, color)
+                        });
+                        pixel_map.blend_pixels(pixels, Writer::default());
+                    }
+                })
+            })
+            .collect();
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            apply_update(pixel_map.update_since(&mut seen), width, &mut mirror);
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        apply_update(pixel_map.update_since(&mut seen), width, &mut mirror);
+        assert!(mirror == pixel_map.to_image().rgba, "a viewer missed a write");
+    }
+
+    #[test]
+    fn fill_and_load_replace_every_pixel() {
+        let pixel_map = PixelMap::new(2, 1);
+        let mut size = pixel_map.watch_size();
+        size.borrow_and_update();
+        let gray = Color::from_rgb(7, 7, 7);
+        pixel_map.fill(gray);
+        assert_eq!(pixel_map.to_image().rgba, [7, 7, 7, 255, 7, 7, 7, 255]);
+        // Clients only hear about actual size changes
+        assert!(!size.has_changed().unwrap());
+
+        let image = Image {
+            width: 1,
+            height: 2,
+            rgba: vec![1, 2, 3, 255, 4, 5, 6, 255],
+            srgb: false,
+        };
+        pixel_map.load(&image);
+        assert!(size.has_changed().unwrap());
+        assert_eq!(pixel_map.get_size(), (1, 2));
+        assert_eq!(pixel_map.to_image().rgba, image.rgba);
+
+        // A broken image is an error, not a reason to take the server down
+        let broken = Image {
+            width: 2,
+            height: 2,
+            rgba: Vec::new(),
+            srgb: false,
+        };
+        assert!(pixel_map.encode_qoi(broken).is_err());
+    }
+
+    #[test]
+    fn attribution_follows_the_pixels() {
+        let mut pixel_map = PixelMap::new(4, 4);
+        let white = Color::from_rgb(255, 255, 255);
+        pixel_map.blend_color(0, 0, white, Writer::default());
+        assert!(!pixel_map.is_attributed());
+        assert_eq!(pixel_map.last_write(0, 0), None);
+
+        pixel_map.enable_attribution();
+        let writer = Writer {
+            connection: 3,
+            ip_hash: 0xabcd,
+        };
+        pixel_map.fill_rect(1, 1, 2, 2, white, writer);
+        assert_eq!(pixel_map.last_write(2, 2).map(|write| write.writer), Some(writer));
+        assert_eq!(pixel_map.last_write(0, 0), None);
+        assert_eq!(pixel_map.last_write(4, 0), None);
+
+        // Resizing keeps the attribution of the pixels that stay
+        pixel_map.resize(2, 2, Color::black());
+        assert_eq!(pixel_map.last_write(1, 1).map(|write| write.writer), Some(writer));
+        assert_eq!(pixel_map.last_write(0, 1), None);
+        pixel_map.fill(white);
+        assert_eq!(pixel_map.last_write(1, 1), None);
+    }
+}
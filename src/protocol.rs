@@ -0,0 +1,438 @@
+use std::fmt::Display;
+use std::str;
+
+use crate::canvases;
+use crate::color::Color;
+
+/// Largest image IMG accepts, in pixels
+pub const MAX_IMAGE_PIXELS: u32 = 4096 * 4096;
+
+/// Worst case QOI size of the largest image: 5 bytes per pixel plus the 14
+/// byte header and the 8 byte end marker
+const MAX_QOI_LEN: u32 = MAX_IMAGE_PIXELS * 5 + 22;
+
+/// A single line of the text protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Sets the pixel if a color is given, reads it otherwise
+    Px {
+        x: u32,
+        y: u32,
+        color: Option<Color>,
+    },
+    /// Fills the rectangle with `color`
+    Rect {
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+        color: Color,
+    },
+    /// Followed by `w * h` raw rgba pixels, or `qoi_len` bytes of a QOI image
+    Img {
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+        qoi_len: Option<u32>,
+    },
+    Size,
+    Offset {
+        x: u32,
+        y: u32,
+    },
+    /// Switches to the named canvas, tells the current one without a name
+    Canvas {
+        name: Option<String>,
+    },
+    /// Asks who last drew the pixel
+    Who {
+        x: u32,
+        y: u32,
+    },
+    Exit,
+    Debug,
+    Bin,
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnknownCommand,
+    MissingX,
+    MissingY,
+    InvalidCoordinate,
+    InvalidColor,
+    InvalidOffset,
+    InvalidRect,
+    InvalidImage,
+    InvalidCanvas,
+    InvalidWho,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            ProtocolError::UnknownCommand => "Unknown Command",
+            ProtocolError::MissingX => "Missing X",
+            ProtocolError::MissingY => "Missing Y",
+            ProtocolError::InvalidCoordinate => "Invalid Coordinate",
+            ProtocolError::InvalidColor => "Invalid Color (rrggbb, rrggbbaa or gray as ww)",
+            ProtocolError::InvalidOffset => "Invalid Offset (OFFSET x y)",
+            ProtocolError::InvalidRect => "Invalid Rect (RECT x y w h hex)",
+            ProtocolError::InvalidImage => "Invalid Image (IMG x y w h [qoi length])",
+            ProtocolError::InvalidCanvas => "Invalid Canvas (CANVAS [name])",
+            ProtocolError::InvalidWho => "Invalid Who (WHO x y)",
+        };
+        write!(f, "ERR: {}", message)
+    }
+}
+
+/// Parses one line of the text protocol. Takes raw bytes, as clients aren't
+/// guaranteed to send valid UTF-8, and never panics on any input.
+pub fn parse(line: &[u8]) -> Result<Command, ProtocolError> {
+    let mut split = line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty());
+    let command = match split.next() {
+        Some(command) => command,
+        None => return Err(ProtocolError::UnknownCommand),
+    };
+    match command {
+        b"PX" => {
+            let x = parse_coordinate(split.next().ok_or(ProtocolError::MissingX)?)?;
+            let y = parse_coordinate(split.next().ok_or(ProtocolError::MissingY)?)?;
+            let color = match split.next() {
+                Some(hex) => Some(parse_color(hex)?),
+                None => None,
+            };
+            Ok(Command::Px { x, y, color })
+        }
+        b"RECT" => {
+            let mut next = || {
+                split
+                    .next()
+                    .ok_or(ProtocolError::InvalidRect)
+                    .and_then(parse_coordinate)
+            };
+            let (x, y, w, h) = (next()?, next()?, next()?, next()?);
+            let color = parse_color(split.next().ok_or(ProtocolError::InvalidRect)?)?;
+            Ok(Command::Rect { x, y, w, h, color })
+        }
+        b"IMG" => {
+            let mut next = || {
+                split
+                    .next()
+                    .ok_or(ProtocolError::InvalidImage)
+                    .and_then(|part| {
+                        parse_coordinate(part).map_err(|_| ProtocolError::InvalidImage)
+                    })
+            };
+            let (x, y, w, h) = (next()?, next()?, next()?, next()?);
+            // Anything but a length would be taken for raw pixels otherwise
+            let qoi_len = split
+                .next()
+                .map(|part| parse_coordinate(part).map_err(|_| ProtocolError::InvalidImage))
+                .transpose()?;
+            if w == 0 || h == 0 || w.saturating_mul(h) > MAX_IMAGE_PIXELS {
+                return Err(ProtocolError::InvalidImage);
+            }
+            if qoi_len.is_some_and(|len| len > MAX_QOI_LEN) {
+                return Err(ProtocolError::InvalidImage);
+            }
+            Ok(Command::Img {
+                x,
+                y,
+                w,
+                h,
+                qoi_len,
+            })
+        }
+        b"SIZE" => Ok(Command::Size),
+        b"OFFSET" => {
+            let x = split.next().ok_or(ProtocolError::InvalidOffset)?;
+            let y = split.next().ok_or(ProtocolError::InvalidOffset)?;
+            match (parse_coordinate(x), parse_coordinate(y)) {
+                (Ok(x), Ok(y)) => Ok(Command::Offset { x, y }),
+                _ => Err(ProtocolError::InvalidOffset),
+            }
+        }
+        b"CANVAS" => match split.next() {
+            Some(name) => match str::from_utf8(name) {
+                Ok(name) if canvases::is_valid_name(name) => Ok(Command::Canvas {
+                    name: Some(name.to_string()),
+                }),
+                _ => Err(ProtocolError::InvalidCanvas),
+            },
+            None => Ok(Command::Canvas { name: None }),
+        },
+        b"WHO" => {
+            let x = split.next().ok_or(ProtocolError::InvalidWho)?;
+            let y = split.next().ok_or(ProtocolError::InvalidWho)?;
+            match (parse_coordinate(x), parse_coordinate(y)) {
+                (Ok(x), Ok(y)) => Ok(Command::Who { x, y }),
+                _ => Err(ProtocolError::InvalidWho),
+            }
+        }
+        b"EXIT" => Ok(Command::Exit),
+        b"DEBUG" => Ok(Command::Debug),
+        b"BIN" => Ok(Command::Bin),
+        b"HELP" => Ok(Command::Help),
+        _ => Err(ProtocolError::UnknownCommand),
+    }
+}
+
+fn parse_coordinate(part: &[u8]) -> Result<u32, ProtocolError> {
+    // u32::from_str would also accept a leading '+'
+    if !part.iter().all(u8::is_ascii_digit) {
+        return Err(ProtocolError::InvalidCoordinate);
+    }
+    str::from_utf8(part)
+        .ok()
+        .and_then(|part| part.parse().ok())
+        .ok_or(ProtocolError::InvalidCoordinate)
+}
+
+/// Decodes the QOI payload following an IMG into `w * h` rgba pixels
+pub fn decode_qoi_image(payload: &[u8], w: u32, h: u32) -> Result<Vec<u8>, ProtocolError> {
+    // Check the header first, so a bogus size can't make us allocate gigabytes
+    match rapid_qoi::Qoi::decode_header(payload) {
+        Ok(header) if header.width == w && header.height == h => {}
+        _ => return Err(ProtocolError::InvalidImage),
+    }
+    let (header, pixels) =
+        rapid_qoi::Qoi::decode_alloc(payload).map_err(|_| ProtocolError::InvalidImage)?;
+    if header.colors.has_alpha() {
+        return Ok(pixels);
+    }
+    Ok(pixels
+        .chunks_exact(3)
+        .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+        .collect())
+}
+
+fn parse_color(part: &[u8]) -> Result<Color, ProtocolError> {
+    str::from_utf8(part)
+        .ok()
+        .and_then(Color::from_hex)
+        .ok_or(ProtocolError::InvalidColor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64, good enough to generate garbage input
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(b"SIZE\n"), Ok(Command::Size));
+        assert_eq!(parse(b"HELP"), Ok(Command::Help));
+        assert_eq!(parse(b"CANVAS"), Ok(Command::Canvas { name: None }));
+        assert_eq!(
+            parse(b"CANVAS sandbox\n"),
+            Ok(Command::Canvas {
+                name: Some("sandbox".to_string())
+            })
+        );
+        assert_eq!(parse(b"WHO 3 4\n"), Ok(Command::Who { x: 3, y: 4 }));
+        assert_eq!(
+            parse(b"OFFSET 10 20\r\n"),
+            Ok(Command::Offset { x: 10, y: 20 })
+        );
+        assert_eq!(
+            parse(b"PX 1 2"),
+            Ok(Command::Px {
+                x: 1,
+                y: 2,
+                color: None
+            })
+        );
+        assert_eq!(
+            parse(b"PX  3   4 ff0000\n"),
+            Ok(Command::Px {
+                x: 3,
+                y: 4,
+                color: Some(Color::from_rgb(255, 0, 0))
+            })
+        );
+    }
+
+    #[test]
+    fn parses_bulk_commands() {
+        assert_eq!(
+            parse(b"RECT 1 2 30 40 ff"),
+            Ok(Command::Rect {
+                x: 1,
+                y: 2,
+                w: 30,
+                h: 40,
+                color: Color::from_rgb(255, 255, 255)
+            })
+        );
+        assert_eq!(
+            parse(b"IMG 1 2 3 4\n"),
+            Ok(Command::Img {
+                x: 1,
+                y: 2,
+                w: 3,
+                h: 4,
+                qoi_len: None
+            })
+        );
+        assert_eq!(
+            parse(b"IMG 1 2 3 4 100\n"),
+            Ok(Command::Img {
+                x: 1,
+                y: 2,
+                w: 3,
+                h: 4,
+                qoi_len: Some(100)
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_qoi_images() {
+        let rgb = [1, 2, 3, 4, 5, 6];
+        let qoi = rapid_qoi::Qoi {
+            width: 2,
+            height: 1,
+            colors: rapid_qoi::Colors::Rgb,
+        }
+        .encode_alloc(&rgb)
+        .unwrap();
+        assert_eq!(
+            decode_qoi_image(&qoi, 2, 1),
+            Ok(vec![1, 2, 3, 255, 4, 5, 6, 255])
+        );
+        assert_eq!(
+            decode_qoi_image(&qoi, 1, 2),
+            Err(ProtocolError::InvalidImage)
+        );
+        assert_eq!(
+            decode_qoi_image(&qoi[..10], 2, 1),
+            Err(ProtocolError::InvalidImage)
+        );
+    }
+
+    #[test]
+    fn parses_color_forms() {
+        let color = |line: &[u8]| match parse(line) {
+            Ok(Command::Px { color, .. }) => color,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(color(b"PX 0 0 7f"), Some(Color::from_rgb(0x7f, 0x7f, 0x7f)));
+        assert_eq!(color(b"PX 0 0 0a0B0c"), Some(Color::from_rgb(10, 11, 12)));
+        assert_eq!(
+            color(b"PX 0 0 01020380"),
+            Some(Color::from_rgba(1, 2, 3, 0x80))
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(parse(b""), Err(ProtocolError::UnknownCommand));
+        assert_eq!(parse(b"px 1 1"), Err(ProtocolError::UnknownCommand));
+        assert_eq!(parse(b"PX"), Err(ProtocolError::MissingX));
+        assert_eq!(parse(b"PX 1"), Err(ProtocolError::MissingY));
+        assert_eq!(parse(b"PX abc 1 ff"), Err(ProtocolError::InvalidCoordinate));
+        assert_eq!(parse(b"PX -1 1 ff"), Err(ProtocolError::InvalidCoordinate));
+        assert_eq!(parse(b"PX +1 1 ff"), Err(ProtocolError::InvalidCoordinate));
+        assert_eq!(
+            parse(b"PX 99999999999 1 ff"),
+            Err(ProtocolError::InvalidCoordinate)
+        );
+        assert_eq!(parse(b"PX 1 1 fff"), Err(ProtocolError::InvalidColor));
+        assert_eq!(parse(b"PX 1 1 +fffff"), Err(ProtocolError::InvalidColor));
+        assert_eq!(parse(b"PX 1 1 gg0000"), Err(ProtocolError::InvalidColor));
+        assert_eq!(parse(b"PX 1 1 \xff\xfe"), Err(ProtocolError::InvalidColor));
+        assert_eq!(parse(b"OFFSET 1"), Err(ProtocolError::InvalidOffset));
+        assert_eq!(parse(b"OFFSET a b"), Err(ProtocolError::InvalidOffset));
+        assert_eq!(parse(b"RECT 1 2 3"), Err(ProtocolError::InvalidRect));
+        assert_eq!(parse(b"RECT 1 2 3 4"), Err(ProtocolError::InvalidRect));
+        assert_eq!(parse(b"RECT 1 2 3 4 xyz"), Err(ProtocolError::InvalidColor));
+        assert_eq!(parse(b"IMG 1 2 0 4"), Err(ProtocolError::InvalidImage));
+        assert_eq!(parse(b"CANVAS Main"), Err(ProtocolError::InvalidCanvas));
+        assert_eq!(parse(b"WHO 1"), Err(ProtocolError::InvalidWho));
+        assert_eq!(
+            parse(b"IMG 1 2 5000 5000"),
+            Err(ProtocolError::InvalidImage)
+        );
+        assert_eq!(
+            parse(b"IMG 1 2 3 4 999999999"),
+            Err(ProtocolError::InvalidImage)
+        );
+        assert_eq!(parse(b"IMG 1 2 3 4 1O0"), Err(ProtocolError::InvalidImage));
+        assert_eq!(
+            ProtocolError::InvalidCoordinate.to_string(),
+            "ERR: Invalid Coordinate"
+        );
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = Rng(0x5eed_1337_cafe_f00d);
+        for _ in 0..100_000 {
+            let line: Vec<u8> = (0..rng.below(40)).map(|_| rng.next() as u8).collect();
+            let _ = parse(&line);
+        }
+    }
+
+    #[test]
+    fn random_tokens_never_panic() {
+        const TOKENS: &[&[u8]] = &[
+            b"PX",
+            b"SIZE",
+            b"OFFSET",
+            b"RECT",
+            b"IMG",
+            b"HELP",
+            b"BIN",
+            b"0",
+            b"1279",
+            b"4294967296",
+            b"-1",
+            b"ff",
+            b"ffffff",
+            b"ffffffff",
+            b"fffffffff",
+            b"+f",
+            b"\xc3\x28",
+            b"\0",
+            b" ",
+            b"\t",
+            b"\r",
+            b"\n",
+        ];
+        let mut rng = Rng(0xdead_beef_0bad_f00d);
+        for _ in 0..100_000 {
+            let mut line = Vec::new();
+            for _ in 0..rng.below(6) {
+                line.extend_from_slice(TOKENS[rng.below(TOKENS.len())]);
+                line.push(b' ');
+            }
+            if let Ok(Command::Px {
+                color: Some(color), ..
+            }) = parse(&line)
+            {
+                // Whatever got accepted has to be a real color
+                assert!(Color::from_hex(&color.hex()).is_some());
+            }
+        }
+    }
+}
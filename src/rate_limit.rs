@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Pixel budgets of the pixelflut clients, all in pixels per second. Each
+/// budget can hold up to one second worth of pixels, so short bursts are fine.
+pub(crate) struct RateLimiter {
+    per_connection: Option<u32>,
+    per_ip: Option<u32>,
+    // Split evenly between all connected clients
+    total: Option<u32>,
+    active: AtomicUsize,
+    ips: Mutex<HashMap<IpAddr, Arc<Mutex<TokenBucket>>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_connection: Option<u32>, per_ip: Option<u32>, total: Option<u32>) -> Self {
+        RateLimiter {
+            per_connection,
+            per_ip,
+            total,
+            active: AtomicUsize::new(0),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a new client, its budget lives as long as the returned throttle
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Throttle {
+        self.active.fetch_add(1, SeqCst);
+        let ip_bucket = self.per_ip.map(|_| {
+            let mut ips = self.ips.lock().unwrap();
+            Arc::clone(
+                ips.entry(ip)
+                    .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new()))),
+            )
+        });
+        Throttle {
+            limiter: Arc::clone(self),
+            ip,
+            bucket: TokenBucket::new(),
+            ip_bucket,
+            last_notice: None,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.per_connection.is_some() || self.per_ip.is_some() || self.total.is_some()
+    }
+
+    fn connection_rate(&self) -> Option<f64> {
+        let fair_share = self
+            .total
+            .map(|total| total as f64 / self.active.load(SeqCst).max(1) as f64);
+        match (self.per_connection.map(|rate| rate as f64), fair_share) {
+            (Some(rate), Some(share)) => Some(rate.min(share)),
+            (rate, share) => rate.or(share),
+        }
+    }
+}
+
+/// The budget of a single connection
+pub(crate) struct Throttle {
+    limiter: Arc<RateLimiter>,
+    ip: IpAddr,
+    bucket: TokenBucket,
+    ip_bucket: Option<Arc<Mutex<TokenBucket>>>,
+    last_notice: Option<Instant>,
+}
+
+impl Throttle {
+    /// Takes `pixels` from the budgets of the connection and its IP. If they
+    /// are used up, waits until the client is allowed to continue, so a flooding
+    /// client gets slowed down by TCP backpressure. Returns true if the client
+    /// should be told that it is being throttled, which is at most once a second.
+    /// Gives up and returns None if `interrupt` completes while waiting.
+    pub async fn take(
+        &mut self,
+        pixels: u64,
+        interrupt: impl Future<Output = ()>,
+    ) -> Option<bool> {
+        if !self.limiter.enabled() || pixels == 0 {
+            return Some(false);
+        }
+        let now = Instant::now();
+        let mut wait = match self.limiter.connection_rate() {
+            Some(rate) => self.bucket.take(pixels, rate, now),
+            None => Duration::ZERO,
+        };
+        if let (Some(bucket), Some(rate)) = (&self.ip_bucket, self.limiter.per_ip) {
+            wait = wait.max(bucket.lock().unwrap().take(pixels, rate as f64, now));
+        }
+        if wait.is_zero() {
+            return Some(false);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = interrupt => return None,
+        }
+        match self.last_notice {
+            Some(last) if now.duration_since(last) < Duration::from_secs(1) => Some(false),
+            _ => {
+                self.last_notice = Some(now);
+                Some(true)
+            }
+        }
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, SeqCst);
+        if let Some(bucket) = self.ip_bucket.take() {
+            let mut ips = self.limiter.ips.lock().unwrap();
+            // Last connection of this IP, one reference is held by the map
+            if Arc::strong_count(&bucket) == 2 {
+                ips.remove(&self.ip);
+            }
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        TokenBucket {
+            tokens: 0.0,
+            last: None,
+        }
+    }
+
+    /// Refills the bucket at `rate` and takes `amount` out of it. The bucket
+    /// can go into debt, the returned duration is how long it takes to pay it off.
+    fn take(&mut self, amount: u64, rate: f64, now: Instant) -> Duration {
+        self.tokens = match self.last {
+            Some(last) => (self.tokens + now.duration_since(last).as_secs_f64() * rate).min(rate),
+            // Start with a full bucket
+            None => rate,
+        };
+        self.last = Some(now);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::Connections;
+    use std::net::SocketAddr;
+
+    const RATE: f64 = 100.0;
+
+    #[test]
+    fn allows_a_burst_of_one_second() {
+        let mut bucket = TokenBucket::new();
+        let now = Instant::now();
+        assert_eq!(bucket.take(60, RATE, now), Duration::ZERO);
+        assert_eq!(bucket.take(40, RATE, now), Duration::ZERO);
+        // Out of tokens, 50 more take half a second to pay off
+        assert_eq!(bucket.take(50, RATE, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut bucket = TokenBucket::new();
+        let start = Instant::now();
+        assert_eq!(bucket.take(100, RATE, start), Duration::ZERO);
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(25, RATE, later), Duration::ZERO);
+        assert_eq!(bucket.take(25, RATE, later), Duration::from_millis(250));
+        // Debt is paid off by the refill, but the bucket never holds more than the rate
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.take(100, RATE, much_later), Duration::ZERO);
+        assert_eq!(bucket.take(1, RATE, much_later), Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn throttled_connections_can_be_kicked() {
+        let connections = Arc::new(Connections::new(None, None));
+        let guard = connections.open(SocketAddr::from(([10, 0, 0, 1], 1))).unwrap();
+        let limiter = Arc::new(RateLimiter::new(Some(1), None, None));
+        let mut throttle = limiter.connect(IpAddr::from([10, 0, 0, 1]));
+        let kick = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            connections.disconnect_where(|_| true);
+        };
+        // Days worth of debt
+        let take = tokio::time::timeout(
+            Duration::from_secs(5),
+            throttle.take(1_000_000, guard.kicked()),
+        );
+        let (taken, ()) = tokio::join!(take, kick);
+        assert_eq!(taken.unwrap(), None);
+        assert_eq!(throttle.take(0, std::future::pending()).await, Some(false));
+    }
+
+    #[test]
+    fn splits_the_total_between_clients() {
+        let limiter = Arc::new(RateLimiter::new(Some(40), None, Some(100)));
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let first = limiter.connect(ip);
+        assert_eq!(limiter.connection_rate(), Some(40.0));
+        let throttles: Vec<Throttle> = (0..4).map(|_| limiter.connect(ip)).collect();
+        assert_eq!(limiter.connection_rate(), Some(20.0));
+        drop(throttles);
+        drop(first);
+        assert_eq!(limiter.active.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn forgets_ips_without_connections() {
+        let limiter = Arc::new(RateLimiter::new(None, Some(10), None));
+        let a = limiter.connect(IpAddr::from([10, 0, 0, 1]));
+        let b = limiter.connect(IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(limiter.ips.lock().unwrap().len(), 1);
+        drop(a);
+        assert_eq!(limiter.ips.lock().unwrap().len(), 1);
+        drop(b);
+        assert!(limiter.ips.lock().unwrap().is_empty());
+    }
+}
@@ -1,118 +1,632 @@
-use std::str;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
 
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use fastwebsockets::{FragmentCollector, Frame, Payload, Role, WebSocketError};
+use fastwebsockets::{FragmentCollector, Frame, OpCode, Payload, Role, WebSocketError};
 use sha1::Digest;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::MissedTickBehavior;
 
-use crate::pixel_map::PixelMap;
+use crate::access::AccessList;
+use crate::admin::Admin;
+use crate::canvases::{Canvases, NamedCanvas, MAIN_CANVAS};
+use crate::connections;
+use crate::history::History;
+use crate::http::{self, Request, Response};
+use crate::image::{ImageFormat, View};
+use crate::metrics::{GaugeGuard, METRICS};
+use crate::pixel_map::{CanvasUpdate, PixelMap};
+use crate::shutdown::ShutdownSignal;
+use crate::static_files::StaticFiles;
 
-pub(crate) async fn render_thread(pixel_map: Arc<PixelMap>, runtime_handle: Handle) {
-    let runtime_handle = Arc::new(runtime_handle);
-    let arc_handle = Arc::clone(&runtime_handle);
+/// Fastest replay speed a viewer can ask for
+const MAX_REPLAY_SPEED: f64 = 3600.0;
+/// Longest pause between two replayed frames
+const MAX_REPLAY_PAUSE: Duration = Duration::from_secs(1);
+/// How long an idle keep-alive connection is kept open
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest message a viewer may send, they only send control frames
+const MAX_VIEWER_MESSAGE: usize = 4096;
 
-    let server = TcpListener::bind("localhost:1338").await.unwrap();
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn render_thread(
+    canvases: Arc<Canvases>,
+    http_listen: String,
+    tick_rate: u32,
+    history: Option<Arc<History>>,
+    static_files: Option<StaticFiles>,
+    admin: Option<Admin>,
+    access: Arc<AccessList>,
+    mut shutdown: ShutdownSignal,
+) {
+    let runtime_handle = Handle::current();
+
+    // Deflated frames of every canvas, shared by all WebSocket viewers of it
+    let mut frames = HashMap::new();
+    for canvas in canvases.iter() {
+        let (sender, _) = broadcast::channel(16);
+        runtime_handle.spawn(broadcast_frames(
+            Arc::clone(&canvas.pixel_map),
+            sender.clone(),
+            tick_rate,
+        ));
+        frames.insert(canvas.name.clone(), sender);
+    }
+
+    let server = Arc::new(HttpServer {
+        canvases,
+        runtime_handle: runtime_handle.clone(),
+        frames,
+        history,
+        static_files,
+        admin,
+    });
+    let listener = TcpListener::bind(http_listen).await.unwrap();
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            connection = connections::accept(&listener) => connection,
+            _ = shutdown.recv() => return,
+        };
+        if let Err(rejection) = access.check(addr.ip()) {
+            runtime_handle.spawn(async move {
+                let response = Response::new(403).text(&rejection.to_string());
+                response.write_to(&mut stream, true, false).await
+            });
+            continue;
+        }
+        runtime_handle.spawn(handle_connection(
+            stream,
+            Arc::clone(&server),
+            shutdown.clone(),
+        ));
+    }
+}
+
+/// Encodes the changes to the canvas once per tick and hands them to every
+/// subscribed viewer. Ticks without viewers are skipped, and so are ticks
+/// without writes once a quiet tick has been scanned: a write that was still
+/// in progress during the last frame stamps its tiles late.
+async fn broadcast_frames(
+    pixel_map: Arc<PixelMap>,
+    frames: broadcast::Sender<Arc<Vec<u8>>>,
+    tick_rate: u32,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / tick_rate);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut seen = pixel_map.generation();
+    let mut last_version = pixel_map.version();
+    let mut settled = true;
     loop {
-        let (stream, _) = server.accept().await.unwrap();
-        let pixel_map = Arc::clone(&pixel_map);
-        let arc_handle = Arc::clone(&arc_handle);
-        runtime_handle.spawn(
-            handle_connection(
-                stream,
-                Arc::clone(&pixel_map),
-                Arc::clone(&arc_handle),
-            )
-        );
+        interval.tick().await;
+        if frames.receiver_count() == 0 {
+            continue;
+        }
+        let version = pixel_map.version();
+        let changed = version != last_version;
+        if !changed && settled {
+            continue;
+        }
+        settled = !changed;
+        last_version = version;
+        let frame = match pixel_map.update_since(&mut seen) {
+            CanvasUpdate::Unchanged => continue,
+            CanvasUpdate::Full(qoi) => fdeflate::compress_to_vec(&qoi),
+            CanvasUpdate::Delta(delta) => fdeflate::compress_to_vec(&delta),
+        };
+        // Only fails if every viewer disconnected in the meantime
+        let _ = frames.send(Arc::new(frame));
     }
 }
 
-// https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers
+/// Everything the HTTP handlers need
+struct HttpServer {
+    canvases: Arc<Canvases>,
+    runtime_handle: Handle,
+    frames: HashMap<String, broadcast::Sender<Arc<Vec<u8>>>>,
+    history: Option<Arc<History>>,
+    static_files: Option<StaticFiles>,
+    admin: Option<Admin>,
+}
+
+/// Serves requests on one connection until the client closes it, stops
+/// sending requests for KEEP_ALIVE_TIMEOUT or upgrades to a WebSocket
 async fn handle_connection(
     mut stream: TcpStream,
-    pixel_map: Arc<PixelMap>,
-    runtime_handle: Arc<Handle>,
+    server: Arc<HttpServer>,
+    mut shutdown: ShutdownSignal,
 ) -> std::io::Result<()> {
-    let mut buffer = [0; 8192];
-    let _amount = stream.read(&mut buffer).await?;
-    let path = str::from_utf8(&buffer)
-        .unwrap()
-        .lines()
-        .next()
-        .unwrap()
-        .split_whitespace()
-        .nth(1)
-        .unwrap();
-    if path.contains("canvas") {
-        let response = b"HTTP/1.1 200 OK\r\nContent-Type: image/qoi\r\n";
-        let qoi = pixel_map.to_qoi(runtime_handle.clone()).0;
-        stream.write_all(response).await?;
-        stream.write_all(b"Dimensions: ").await?;
-        stream.write_all(pixel_map.get_width().to_string().as_bytes()).await?;
-        stream.write_all(b"x").await?;
-        stream.write_all(pixel_map.get_height().to_string().as_bytes()).await?;
-        stream.write_all(b"\r\n").await?;
-        stream.write_all(b"Content-Length: ").await?;
-        stream.write_all(qoi.len().to_string().as_bytes()).await?;
-        stream.write_all(b"\r\n\r\n").await?;
-        stream.write_all(&qoi).await?;
-        stream.flush().await?;
-        stream.shutdown().await?;
-    } else if path.contains("ws") {
-        let response =
-            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n";
-        let key = unsafe {
-            //println!("{}", str::from_utf8_unchecked(&buffer));
-            // IT SHOULD ALREADY BE VALID UTF-8. IF NOT IT WOULD'VE FAILED WHEN PARSING FOR THE PATH
-            str::from_utf8_unchecked(&buffer)
-                .lines()
-                .find(|x| x.to_lowercase().contains("sec-websocket-key"))
-                .unwrap()
-                .split(": ")
-                .nth(1)
-                .unwrap()
-        };
-        let mut sha = sha1::Sha1::new();
-        Digest::update(&mut sha, key.as_bytes());
-        Digest::update(&mut sha, b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-        let accept = BASE64_STANDARD.encode(&sha.finalize().0[..]);
-        stream.write_all(response).await?;
-        stream.write_all(b"Sec-WebSocket-Accept: ").await?;
-        stream.write_all(accept.as_bytes()).await?;
-        stream.write_all(b"\r\n\r\n").await?;
-        let cloned_handle = runtime_handle.clone();
-        cloned_handle.spawn(async move {
-            let ws = fastwebsockets::WebSocket::after_handshake(stream, Role::Server);
-            let mut ws = FragmentCollector::new(ws);
-            send_websocket_bytes_deflated(&mut ws, &pixel_map.to_qoi(runtime_handle.clone()).0)
-                .await
-                .unwrap();
-            loop {
-                let str = unsafe {
-                    match ws.read_frame().await {
-                        Ok(e) => String::from_utf8_unchecked(e.payload.to_vec()),
+    // Read but not yet handled bytes, requests may be pipelined
+    let mut buf = Vec::new();
+    loop {
+        let request = tokio::select! {
+            request = tokio::time::timeout(
+                KEEP_ALIVE_TIMEOUT,
+                http::read_request(&mut stream, &mut buf),
+            ) => request,
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let mut request = match request {
+            Ok(Ok(Some(request))) => request,
+            // Closed or idle for too long
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => {
+                if let Some(response) = e.response() {
+                    response.write_to(&mut stream, true, false).await?;
+                }
+                return Ok(());
+            }
+        };
+        // The reverse proxy keeps the "/api" prefix, so the routes work with and without it
+        let route = request
+            .path
+            .strip_prefix("/api")
+            .filter(|route| route.starts_with('/'))
+            .unwrap_or(&request.path)
+            .to_string();
+        let route = route.as_str();
+        if request.content_length() > 0 {
+            let max = match server.max_body(&request, route) {
+                Ok(max) => max,
+                Err(response) => {
+                    // The body is left unread, so the connection can't be used anymore
+                    return response.write_to(&mut stream, true, false).await;
+                }
+            };
+            let body = tokio::select! {
+                body = tokio::time::timeout(
+                    KEEP_ALIVE_TIMEOUT,
+                    http::read_body(&mut stream, &mut buf, &mut request, max),
+                ) => body,
+                _ = shutdown.recv() => return Ok(()),
+            };
+            match body {
+                Ok(Ok(())) => {}
+                Err(_) => return Ok(()),
+                Ok(Err(e)) => {
+                    if let Some(response) = e.response() {
+                        response.write_to(&mut stream, true, false).await?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        let websocket =
+            route == "/replay" || matches!(canvas_route(route), Some(CanvasRoute::Live(_)));
+        if websocket && request.method == "GET" && request.is_upgrade() {
+            return server.upgrade(stream, &request, route, shutdown).await;
+        }
+        let response = server.respond(&request, route).await;
+        let keep_alive = request.keep_alive() && !shutdown.is_triggered();
+        response
+            .write_to(&mut stream, request.method != "HEAD", keep_alive)
+            .await?;
+        if !keep_alive {
+            return stream.shutdown().await;
+        }
+    }
+}
+
+impl HttpServer {
+    /// How large the body of a request may be. Only the admin endpoints take
+    /// one, as uploads of images to load.
+    fn max_body(&self, request: &Request, route: &str) -> Result<usize, Response> {
+        match &self.admin {
+            Some(admin) if route.starts_with("/admin/") => admin.max_body(request),
+            _ => Ok(0),
+        }
+    }
+
+    async fn respond(&self, request: &Request, route: &str) -> Response {
+        if route.starts_with("/admin/") {
+            return match &self.admin {
+                Some(admin) => admin.respond(request, route).await,
+                None => Response::new(404).text("Admin interface is disabled"),
+            };
+        }
+        let canvas_route = canvas_route(route);
+        let api = canvas_route.is_some()
+            || matches!(route, "/canvases" | "/who" | "/metrics" | "/history" | "/replay");
+        // Everything outside of the API is the frontend, if it is served at all
+        let static_files = match &self.static_files {
+            Some(static_files) if !api && !request.path.starts_with("/api/") => Some(static_files),
+            _ if !api => return Response::new(404).text("Not Found"),
+            _ => None,
+        };
+        if !matches!(request.method.as_str(), "GET" | "HEAD") {
+            return Response::new(405)
+                .header("Allow", "GET, HEAD")
+                .text("Method Not Allowed");
+        }
+        if let Some(static_files) = static_files {
+            return static_files.serve(request).await;
+        }
+        if let Some(CanvasRoute::Export(name, format)) = canvas_route {
+            return match self.canvases.get(name) {
+                Some(canvas) => self.export(request, &canvas.pixel_map, format).await,
+                None => Response::new(404).text("Unknown canvas"),
+            };
+        }
+        match route {
+            "/canvases" => {
+                let canvases: Vec<String> = self
+                    .canvases
+                    .iter()
+                    .map(|canvas| {
+                        let (width, height) = canvas.pixel_map.get_size();
+                        format!(
+                            "{{\"name\":\"{}\",\"width\":{},\"height\":{}}}",
+                            canvas.name, width, height
+                        )
+                    })
+                    .collect();
+                Response::new(200).body("application/json", format!("[{}]", canvases.join(",")))
+            }
+            "/who" => self.who(request),
+            "/metrics" => {
+                Response::new(200).body("text/plain; version=0.0.4", METRICS.render())
+            }
+            "/history" => match self.history.as_ref().map(|history| history.range()) {
+                Some(Ok(Some((start, end)))) => Response::new(200).body(
+                    "application/json",
+                    format!("{{\"start\":{},\"end\":{}}}", start, end),
+                ),
+                Some(Ok(None)) => Response::new(404).text("No history recorded yet"),
+                Some(Err(e)) => {
+                    println!("Failed to read history: {}", e);
+                    Response::new(500).text("Failed to read history")
+                }
+                None => Response::new(404).text("History is disabled"),
+            },
+            // WebSocket routes that weren't asked for an upgrade
+            _ => Response::new(400).text("Expected a WebSocket handshake"),
+        }
+    }
+
+    /// `/api/who?x=<x>&y=<y>[&canvas=<name>]` tells who last drew the pixel
+    /// and when, as JSON. `last_write` is null if nobody did since the start.
+    fn who(&self, request: &Request) -> Response {
+        let canvas = match request.query_param("canvas") {
+            Some(name) => match self.canvases.get(&name) {
+                Some(canvas) => canvas,
+                None => return Response::new(404).text("Unknown canvas"),
+            },
+            None => self.canvases.main(),
+        };
+        let pixel_map = &canvas.pixel_map;
+        if !pixel_map.is_attributed() {
+            return Response::new(404).text("Attribution is disabled");
+        }
+        let coords = (|| {
+            Ok::<_, String>((
+                request.parse_query_param::<u32>("x")?.ok_or("Missing x")?,
+                request.parse_query_param::<u32>("y")?.ok_or("Missing y")?,
+            ))
+        })();
+        let (x, y) = match coords {
+            Ok(coords) => coords,
+            Err(e) => return Response::new(400).text(&e),
+        };
+        let (width, height) = pixel_map.get_size();
+        if x >= width || y >= height {
+            return Response::new(400).text("Out of bounds");
+        }
+        let last_write = match pixel_map.last_write(x, y) {
+            Some(write) => format!(
+                "{{\"connection\":{},\"ip_hash\":\"{:08x}\",\"time\":{}}}",
+                write.writer.connection, write.writer.ip_hash, write.time
+            ),
+            None => "null".to_string(),
+        };
+        Response::new(200).body(
+            "application/json",
+            format!("{{\"x\":{},\"y\":{},\"last_write\":{}}}", x, y, last_write),
+        )
+    }
+
+    /// The canvas as an image, cropped to `x`, `y`, `w` and `h` and scaled by
+    /// `scale` if those are given in the query
+    async fn export(
+        &self,
+        request: &Request,
+        pixel_map: &Arc<PixelMap>,
+        format: ImageFormat,
+    ) -> Response {
+        let params = (|| {
+            Ok::<_, String>((
+                request.parse_query_param("x")?,
+                request.parse_query_param("y")?,
+                request.parse_query_param("w")?,
+                request.parse_query_param("h")?,
+                request.parse_query_param("scale")?,
+            ))
+        })();
+        let (x, y, w, h, scale) = match params {
+            Ok(params) => params,
+            Err(e) => return Response::new(400).text(&e),
+        };
+        let view = move |size| View::new(size, x, y, w, h, scale);
+        let size = pixel_map.get_size();
+        let image = match view(size) {
+            Err(e) => return Response::new(400).text(&e),
+            // The live view keeps this one cached
+            Ok(view) if format == ImageFormat::Qoi && view.is_full(size) => {
+                match pixel_map.to_qoi() {
+                    Ok((qoi, _)) => Ok((view, qoi.to_vec())),
+                    Err(e) => {
+                        println!("Failed to export the canvas: {}", e);
+                        Err(Response::new(500).text("Failed to export the canvas"))
+                    }
+                }
+            }
+            Ok(_) => {
+                let pixel_map = Arc::clone(pixel_map);
+                let encode = move || {
+                    let image = pixel_map.to_image();
+                    // Checked again, the canvas may have been resized in the meantime
+                    let size = (image.width, image.height);
+                    let view = view(size).map_err(|e| Response::new(400).text(&e))?;
+                    let rgba = match view.is_full(size) {
+                        true => image.rgba,
+                        false => view.apply(size.0, &image.rgba),
+                    };
+                    match format.encode(view.out_width, view.out_height, &rgba, image.srgb) {
+                        Ok(encoded) => Ok((view, encoded)),
                         Err(e) => {
-                            return;
+                            println!("Failed to export the canvas: {}", e);
+                            Err(Response::new(500).text("Failed to export the canvas"))
                         }
                     }
                 };
+                tokio::task::spawn_blocking(encode)
+                    .await
+                    .unwrap_or_else(|_| Err(Response::new(500).text("Failed to export the canvas")))
+            }
+        };
+        match image {
+            Ok((view, image)) => {
+                METRICS.bytes_sent.fetch_add(image.len() as u64, Relaxed);
+                Response::new(200)
+                    .header("Dimensions", format!("{}x{}", view.out_width, view.out_height))
+                    .body(format.content_type(), image)
+            }
+            Err(response) => response,
+        }
+    }
+
+    // https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers
+    async fn upgrade(
+        &self,
+        mut stream: TcpStream,
+        request: &Request,
+        route: &str,
+        mut shutdown: ShutdownSignal,
+    ) -> std::io::Result<()> {
+        let feed = match (route, canvas_route(route)) {
+            ("/replay", _) => self.history.clone().map(Feed::Replay),
+            (_, Some(CanvasRoute::Live(name))) => self.canvases.get(name).cloned().map(Feed::Live),
+            _ => None,
+        };
+        let Some(feed) = feed else {
+            let message = match route {
+                "/replay" => "History is disabled",
+                _ => "Unknown canvas",
+            };
+            return Response::new(404)
+                .text(message)
+                .write_to(&mut stream, true, false)
+                .await;
+        };
+        let Some(key) = request.header("sec-websocket-key") else {
+            return Response::new(400)
+                .text("Missing Sec-WebSocket-Key")
+                .write_to(&mut stream, true, false)
+                .await;
+        };
+        websocket_handshake(&mut stream, key).await?;
 
-                if str.contains("update") {
-                    let qoi = pixel_map.to_qoi(runtime_handle.clone());
-                    if qoi.1 {
-                        // send null-byte to indicate, that nothing has changed
-                        send_websocket_bytes_deflated(&mut ws, &*vec![0u8]).await.unwrap();
-                    } else {
-                        send_websocket_bytes_deflated(&mut ws, &qoi.0).await.unwrap();
+        let canvas = match feed {
+            Feed::Live(canvas) => canvas,
+            Feed::Replay(history) => {
+                let start = request
+                    .query_param("start")
+                    .and_then(|start| start.parse().ok())
+                    .unwrap_or(0);
+                let speed = request
+                    .query_param("speed")
+                    .and_then(|speed| speed.parse::<f64>().ok())
+                    .filter(|speed| speed.is_finite() && *speed > 0.0)
+                    .unwrap_or(1.0)
+                    .min(MAX_REPLAY_SPEED);
+                self.runtime_handle.spawn(async move {
+                    let _viewer = GaugeGuard::new(&METRICS.websocket_viewers);
+                    let mut ws = FragmentCollector::new(viewer_socket(stream));
+                    let (code, reason) =
+                        replay(&mut ws, history, start, speed, &mut shutdown).await;
+                    let _ = ws.write_frame(Frame::close(code, reason)).await;
+                });
+                return Ok(());
+            }
+        };
+        let pixel_map = Arc::clone(&canvas.pixel_map);
+        let frames = self.frames[&canvas.name].clone();
+        self.runtime_handle.spawn(async move {
+            let _viewer = GaugeGuard::new(&METRICS.websocket_viewers);
+            let mut ws = FragmentCollector::new(viewer_socket(stream));
+            // Subscribe before taking the full frame, so no change falls in between
+            let mut frames = frames.subscribe();
+            let Ok((qoi, _)) = pixel_map.to_qoi() else {
+                return;
+            };
+            if send_websocket_bytes_deflated(&mut ws, &qoi).await.is_err() {
+                return;
+            }
+            loop {
+                let frame = tokio::select! {
+                    frame = frames.recv() => frame,
+                    message = ws.read_frame() => match viewer_left(message) {
+                        true => return,
+                        false => continue,
+                    },
+                    _ = shutdown.recv() => {
+                        let _ = ws
+                            .write_frame(Frame::close(1001, b"Server shutting down"))
+                            .await;
+                        return;
+                    }
+                };
+                let result = match frame {
+                    Ok(frame) => send_websocket_bytes(&mut ws, &frame).await,
+                    Err(RecvError::Lagged(_)) => {
+                        // Missed some deltas, start over with a full frame
+                        let Ok((qoi, _)) = pixel_map.to_qoi() else {
+                            return;
+                        };
+                        send_websocket_bytes_deflated(&mut ws, &qoi).await
                     }
+                    Err(RecvError::Closed) => return,
+                };
+                if result.is_err() {
+                    return;
                 }
             }
         });
+        Ok(())
+    }
+}
+
+/// A route about one canvas
+#[derive(Debug, PartialEq)]
+enum CanvasRoute<'a> {
+    /// `/canvas[.<ext>]` or `/canvas/<name>[.<ext>]`
+    Export(&'a str, ImageFormat),
+    /// `/ws` or `/ws/<name>`
+    Live(&'a str),
+}
+
+/// The routes without a name are about the main canvas
+fn canvas_route(route: &str) -> Option<CanvasRoute<'_>> {
+    if let Some(rest) = route.strip_prefix("/canvas") {
+        let (name, extension) = match rest.strip_prefix('/') {
+            Some(rest) => match rest.split_once('.') {
+                Some((name, extension)) => (name, Some(extension)),
+                None => (rest, None),
+            },
+            None if rest.is_empty() => (MAIN_CANVAS, None),
+            None => (MAIN_CANVAS, Some(rest.strip_prefix('.')?)),
+        };
+        let format = match extension {
+            Some(extension) => ImageFormat::from_extension(extension)?,
+            None => ImageFormat::Qoi,
+        };
+        return (!name.is_empty()).then_some(CanvasRoute::Export(name, format));
+    }
+    match route {
+        "/ws" => Some(CanvasRoute::Live(MAIN_CANVAS)),
+        _ => route
+            .strip_prefix("/ws/")
+            .filter(|name| !name.is_empty())
+            .map(CanvasRoute::Live),
+    }
+}
+
+/// What a WebSocket viewer is sent
+enum Feed {
+    Replay(Arc<History>),
+    Live(Arc<NamedCanvas>),
+}
+
+async fn websocket_handshake(stream: &mut TcpStream, key: &str) -> std::io::Result<()> {
+    let response =
+        b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n";
+    let mut sha = sha1::Sha1::new();
+    Digest::update(&mut sha, key.as_bytes());
+    Digest::update(&mut sha, b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let accept = BASE64_STANDARD.encode(&sha.finalize().0[..]);
+    stream.write_all(response).await?;
+    stream.write_all(b"Sec-WebSocket-Accept: ").await?;
+    stream.write_all(accept.as_bytes()).await?;
+    stream.write_all(b"\r\n\r\n").await
+}
+
+/// Streams the history from `start` (unix ms) on, `speed` times faster than
+/// it was recorded. Every update is preceded by a text message with its time.
+/// Returns the code and reason to close the WebSocket with.
+async fn replay(
+    ws: &mut FragmentCollector<TcpStream>,
+    history: Arc<History>,
+    start: u64,
+    speed: f64,
+    shutdown: &mut ShutdownSignal,
+) -> (u16, &'static [u8]) {
+    let (sender, mut updates) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || history.replay(start, sender));
+    let mut last_time = None;
+    loop {
+        let update = tokio::select! {
+            update = updates.recv() => update,
+            message = ws.read_frame() => match viewer_left(message) {
+                true => return (1000, b""),
+                false => continue,
+            },
+            _ = shutdown.recv() => return (1001, b"Server shutting down"),
+        };
+        let Some(update) = update else {
+            return (1000, b"End of history");
+        };
+        if let Some(last_time) = last_time {
+            // Long pauses, like the server being down, are cut short
+            let wait = Duration::from_millis(update.time_ms.saturating_sub(last_time))
+                .div_f64(speed)
+                .min(MAX_REPLAY_PAUSE);
+            let sleep = tokio::time::sleep(wait);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    message = ws.read_frame() => if viewer_left(message) {
+                        return (1000, b"");
+                    },
+                    _ = shutdown.recv() => return (1001, b"Server shutting down"),
+                }
+            }
+        }
+        last_time = Some(update.time_ms);
+        let time = update.time_ms.to_string();
+        let result = match ws.write_frame(Frame::text(Payload::Borrowed(time.as_bytes()))).await {
+            Ok(()) => send_websocket_bytes(ws, &update.data).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            return (1000, b"");
+        }
+    }
+}
+
+/// WebSocket of a viewer whose handshake is done
+fn viewer_socket(stream: TcpStream) -> fastwebsockets::WebSocket<TcpStream> {
+    let mut ws = fastwebsockets::WebSocket::after_handshake(stream, Role::Server);
+    ws.set_max_message_size(MAX_VIEWER_MESSAGE);
+    ws
+}
+
+/// Handles a message of a viewer and returns whether the viewer is gone.
+/// Pings and closes are already answered by `read_frame`, anything else is
+/// ignored. `read_frame` isn't cancel safe, so a message that arrives in
+/// pieces while an update is sent can get garbled, which only ends that
+/// connection.
+fn viewer_left(message: Result<Frame, WebSocketError>) -> bool {
+    match message {
+        Ok(frame) => frame.opcode == OpCode::Close,
+        // Also the end of the stream
+        Err(_) => true,
     }
-    Ok(())
 }
 
 async fn send_websocket_bytes_deflated(
@@ -120,9 +634,18 @@ async fn send_websocket_bytes_deflated(
     data: &[u8],
 ) -> Result<(), WebSocketError> {
     let comp = fdeflate::compress_to_vec(data);
+    METRICS.bytes_sent.fetch_add(comp.len() as u64, Relaxed);
     ws.write_frame(Frame::binary(Payload::Owned(comp))).await
 }
 
+async fn send_websocket_bytes(
+    ws: &mut FragmentCollector<TcpStream>,
+    data: &[u8],
+) -> Result<(), WebSocketError> {
+    METRICS.bytes_sent.fetch_add(data.len() as u64, Relaxed);
+    ws.write_frame(Frame::binary(Payload::Borrowed(data))).await
+}
+
 #[allow(dead_code)]
 async fn send_websocket_str_deflated(
     ws: &mut FragmentCollector<TcpStream>,
@@ -131,3 +654,4 @@ async fn send_websocket_str_deflated(
     println!("Sending: {}", data);
     send_websocket_bytes_deflated(ws, data.as_bytes()).await
 }
+
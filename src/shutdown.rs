@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+
+/// Tells the running tasks that the server is shutting down and waits until
+/// they are done. Every task that should be waited for holds a
+/// [`ShutdownSignal`].
+pub(crate) struct Shutdown {
+    trigger: watch::Sender<bool>,
+    alive: mpsc::Sender<()>,
+    done: mpsc::Receiver<()>,
+}
+
+/// Handed to every task that has to finish before the server exits
+#[derive(Clone)]
+pub(crate) struct ShutdownSignal {
+    triggered: watch::Receiver<bool>,
+    // Never used to send anything, the channel closes once every signal is dropped
+    _alive: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, _) = watch::channel(false);
+        let (alive, done) = mpsc::channel(1);
+        Shutdown {
+            trigger,
+            alive,
+            done,
+        }
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            triggered: self.trigger.subscribe(),
+            _alive: self.alive.clone(),
+        }
+    }
+
+    /// Notifies every task and waits up to `timeout` for them to finish.
+    /// Returns false if some of them are still running.
+    pub async fn drain(self, timeout: Duration) -> bool {
+        let Shutdown {
+            trigger,
+            alive,
+            mut done,
+        } = self;
+        trigger.send_replace(true);
+        drop(alive);
+        tokio::time::timeout(timeout, done.recv()).await.is_ok()
+    }
+}
+
+impl ShutdownSignal {
+    /// Completes once the server is shutting down
+    pub async fn recv(&mut self) {
+        // Only fails if the shutdown was dropped, which also means shutting down
+        let _ = self.triggered.wait_for(|triggered| *triggered).await;
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+}
+
+/// Waits for SIGINT or SIGTERM and returns the name of the signal
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+
+use crate::pixel_map::PixelMap;
+
+/// Saves the canvas to disk. Every save goes to a temporary file first, which
+/// then replaces the snapshot, so a crash can't leave a half written file
+/// behind. The previous `keep` snapshots are kept as `<name>.1.<ext>` (newest)
+/// up to `<name>.<keep>.<ext>` (oldest).
+pub(crate) struct Snapshots {
+    path: PathBuf,
+    keep: usize,
+    // Also keeps two saves from writing at the same time
+    saved_version: Mutex<usize>,
+}
+
+impl Snapshots {
+    /// `version` is the version of the canvas that is already on disk
+    pub fn new(path: impl Into<PathBuf>, keep: usize, version: usize) -> Self {
+        Snapshots {
+            path: path.into(),
+            keep,
+            saved_version: Mutex::new(version),
+        }
+    }
+
+    /// Saves the canvas if it changed since the last save. Returns whether
+    /// anything was written.
+    pub fn save(&self, pixel_map: &PixelMap) -> io::Result<bool> {
+        let mut saved_version = self.saved_version.lock().unwrap();
+        let version = pixel_map.version();
+        if *saved_version == version {
+            return Ok(false);
+        }
+        let qoi = pixel_map.to_base_qoi().map_err(io::Error::other)?;
+        self.write(&qoi)?;
+        *saved_version = version;
+        Ok(true)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Saves the canvas every `interval`, until the runtime shuts down
+    pub async fn run(self: Arc<Self>, pixel_map: Arc<PixelMap>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes right away
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let snapshots = Arc::clone(&self);
+            let pixel_map = Arc::clone(&pixel_map);
+            let result = tokio::task::spawn_blocking(move || snapshots.save(&pixel_map)).await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to save snapshot {}: {}", self.path.display(), e),
+                Err(e) => println!("Snapshot task failed: {}", e),
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let tmp = self.path.with_extension(match self.path.extension() {
+            Some(ext) => format!("{}.tmp", ext.to_string_lossy()),
+            None => "tmp".to_string(),
+        });
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+        if self.keep > 0 && self.path.exists() {
+            self.rotate()?;
+        }
+        if let Err(e) = fs::rename(&tmp, &self.path) {
+            // Renaming onto a file that is bind mounted into a container fails,
+            // fall back to overwriting it in place
+            println!(
+                "Could not replace {} atomically ({}), overwriting it instead",
+                self.path.display(),
+                e
+            );
+            fs::copy(&tmp, &self.path)?;
+            fs::remove_file(&tmp)?;
+        }
+        // Make the rename itself durable
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            let _ = fs::File::open(dir).and_then(|dir| dir.sync_all());
+        }
+        Ok(())
+    }
+
+    /// Moves every historical snapshot one place back, dropping the oldest, and
+    /// makes the current snapshot the newest historical one
+    fn rotate(&self) -> io::Result<()> {
+        for i in (1..self.keep).rev() {
+            match fs::rename(self.history_path(i), self.history_path(i + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        let newest = self.history_path(1);
+        let _ = fs::remove_file(&newest);
+        // The current snapshot has to stay in place until the new one replaces it
+        if fs::hard_link(&self.path, &newest).is_err() {
+            fs::copy(&self.path, &newest)?;
+        }
+        Ok(())
+    }
+
+    /// Path of the `i`th newest historical snapshot
+    fn history_path(&self, i: usize) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, i, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, i),
+        };
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribution::Writer;
+    use crate::color::Color;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pixelrust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_newest() {
+        let dir = temp_dir("snapshot-rotate");
+        let snapshots = Snapshots::new(dir.join("canvas.qoi"), 2, 0);
+        for data in ["a", "b", "c", "d"] {
+            snapshots.write(data.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("canvas.qoi"), "d");
+        assert_eq!(read("canvas.1.qoi"), "c");
+        assert_eq!(read("canvas.2.qoi"), "b");
+        // Neither older snapshots nor temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_only_changes() {
+        let dir = temp_dir("snapshot-save");
+        let pixel_map = PixelMap::new(4, 4);
+        let snapshots = Snapshots::new(dir.join("canvas.qoi"), 0, pixel_map.version());
+        assert!(!snapshots.save(&pixel_map).unwrap());
+        pixel_map.blend_color(1, 1, Color::from_rgb(255, 0, 0), Writer::default());
+        assert!(snapshots.save(&pixel_map).unwrap());
+        assert!(!snapshots.save(&pixel_map).unwrap());
+        assert_eq!(fs::read(snapshots.path()).unwrap(), **pixel_map.to_base_qoi().unwrap());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
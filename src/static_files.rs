@@ -0,0 +1,205 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::{Request, Response};
+
+/// Files whose name contains a content hash can be cached forever, every
+/// other file has to be revalidated, as it may change with the next build
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+/// Serves the files of a directory, like the built frontend in `app/dist`
+pub(crate) struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles { root })
+    }
+
+    /// Answers a GET or HEAD request for the file at the request path.
+    /// Directories are answered with their index.html.
+    pub async fn serve(&self, request: &Request) -> Response {
+        let Some(path) = self.resolve(&request.path) else {
+            return Response::new(404).text("Not Found");
+        };
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Response::new(404).text("Not Found"),
+        };
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let modified_secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified_secs);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let cache_control = match is_hashed(&name) {
+            true => IMMUTABLE,
+            false => REVALIDATE,
+        };
+
+        let not_modified = match request.header("if-none-match") {
+            Some(tags) => tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"),
+            None => request
+                .header("if-modified-since")
+                .and_then(parse_http_date)
+                .is_some_and(|since| modified_secs <= since),
+        };
+        let response = match not_modified {
+            true => Response::new(304),
+            false => match tokio::fs::read(&path).await {
+                Ok(content) => Response::new(200).body(content_type(&path), content),
+                Err(e) => {
+                    println!("Failed to read {}: {}", path.display(), e);
+                    return Response::new(500).text("Failed to read file");
+                }
+            },
+        };
+        response
+            .header("ETag", etag)
+            .header("Last-Modified", format_http_date(modified))
+            .header("Cache-Control", cache_control)
+    }
+
+    /// Maps a request path to a file below the root. Paths that try to leave
+    /// the root, also through symlinks, are rejected.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_path(request_path)?;
+        let mut path = self.root.clone();
+        for component in Path::new(decoded.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        let path = path.canonicalize().ok()?;
+        path.starts_with(&self.root).then_some(path)
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        // Browsers only compile WebAssembly while streaming it with the right type
+        "wasm" => "application/wasm",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "qoi" => "image/qoi",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether a file name contains a content hash, like webpack's
+/// `8f2a1c0d9e7b6a5f4c3d.module.wasm`
+fn is_hashed(name: &str) -> bool {
+    name.split(['.', '-', '_'])
+        .any(|part| part.len() >= 16 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Decodes %XX escapes, rejects paths that decode to something unusual
+fn percent_decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    let decoded = String::from_utf8(out).ok()?;
+    (!decoded.contains(['\0', '\\'])).then_some(decoded)
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a time like `Sun, 06 Nov 1994 08:49:37 GMT`
+fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses a date in the format of [`format_http_date`] into unix seconds
+fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    let days = days_from_civil(year, month, day);
+    u64::try_from(days).ok().map(|days| days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+// Conversions between days since the unix epoch and dates, from
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,524 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fdeflate::BoundedDecompressionError;
+use rapid_qoi::{Colors, Qoi};
+use tokio::time::MissedTickBehavior;
+
+use crate::image::{Image, ImageFormat};
+use crate::pixel_map::PixelMap;
+
+/* Timelapse Archive
+// Format:
+// [b"PXTL"][u8: version]
+// then per frame:
+// [u8: kind][u64: unix time in ms][u32: width][u32: height][u32: length][length bytes]
+//
+// All integers are little endian. A keyframe (kind 'K') holds the whole canvas
+// as QOI. A delta frame (kind 'X') holds the rgba bytes of the canvas XORed
+// with the previous frame and compressed with deflate, so unchanged pixels
+// turn into long runs of zeros. Every recording session starts with a
+// keyframe and there is one at least every KEYFRAME_INTERVAL frames, so a
+// damaged frame only breaks the frames up to the next keyframe.
+//
+// Frames are only ever appended. If the server crashes in the middle of a
+// frame, the incomplete frame is cut off the next time the archive is opened.
+ */
+const MAGIC: &[u8; 4] = b"PXTL";
+const VERSION: u8 = 1;
+const FRAME_HEADER_SIZE: u64 = 21;
+const KEYFRAME: u8 = b'K';
+const XOR_FRAME: u8 = b'X';
+const KEYFRAME_INTERVAL: u32 = 60;
+/// Most pixels a single byte of QOI can hold, as a run
+const QOI_MAX_RUN: u64 = 62;
+
+const EXPORT_USAGE: &str = "Usage: pixelrust export-timelapse <ARCHIVE> <DIR> [--format png|qoi]
+
+Writes every frame of a timelapse archive to DIR as frame_000000.png,
+frame_000001.png, ... (default format: png)
+";
+
+/// Appends a frame of the canvas to the timelapse archive at an interval
+pub(crate) struct Recorder {
+    path: PathBuf,
+    archive: Mutex<Archive>,
+}
+
+struct Archive {
+    file: File,
+    // Size, pixels and version of the last recorded frame
+    previous: Option<(u32, u32, Vec<u8>, usize)>,
+    since_keyframe: u32,
+}
+
+/// A decoded frame of a timelapse archive
+#[derive(Clone)]
+pub(crate) struct Frame {
+    pub time_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl Recorder {
+    /// Opens the archive at `path` for appending, creating it if needed
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            file.sync_all()?;
+        } else {
+            let end = find_end(&mut file)?;
+            if end < file.metadata()?.len() {
+                println!(
+                    "Timelapse {} ends with an incomplete frame, cutting it off",
+                    path.display()
+                );
+                file.set_len(end)?;
+            }
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(Recorder {
+            path,
+            archive: Mutex::new(Archive {
+                file,
+                previous: None,
+                since_keyframe: 0,
+            }),
+        })
+    }
+
+    /// Appends the canvas as a new frame, unless it didn't change since the
+    /// last one. Returns whether a frame was written.
+    pub fn record(&self, pixel_map: &PixelMap) -> io::Result<bool> {
+        let mut archive = self.archive.lock().unwrap();
+        let version = pixel_map.version();
+        if matches!(archive.previous, Some((_, _, _, previous)) if previous == version) {
+            return Ok(false);
+        }
+        let Image {
+            width,
+            height,
+            rgba,
+            ..
+        } = pixel_map.to_image();
+        let (kind, payload) = match &archive.previous {
+            Some((w, h, previous, _))
+                if (*w, *h) == (width, height) && archive.since_keyframe < KEYFRAME_INTERVAL =>
+            {
+                let xor: Vec<u8> = rgba.iter().zip(previous).map(|(a, b)| a ^ b).collect();
+                (XOR_FRAME, fdeflate::compress_to_vec(&xor))
+            }
+            _ => {
+                let qoi = Qoi {
+                    width,
+                    height,
+                    colors: Colors::Rgba,
+                };
+                (KEYFRAME, qoi.encode_alloc(&rgba).map_err(io::Error::other)?)
+            }
+        };
+        let time_ms = now_ms();
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE as usize + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&time_ms.to_le_bytes());
+        frame.extend_from_slice(&width.to_le_bytes());
+        frame.extend_from_slice(&height.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        let end = archive.file.stream_position()?;
+        let written = archive
+            .file
+            .write_all(&frame)
+            .and_then(|()| archive.file.sync_data());
+        if let Err(e) = written {
+            // Don't leave a partial frame for the next ones to land behind
+            archive.file.set_len(end)?;
+            archive.file.seek(SeekFrom::Start(end))?;
+            return Err(e);
+        }
+
+        archive.since_keyframe = match kind {
+            KEYFRAME => 1,
+            _ => archive.since_keyframe + 1,
+        };
+        archive.previous = Some((width, height, rgba, version));
+        Ok(true)
+    }
+
+    /// Records a frame every `interval`, until the runtime shuts down
+    pub async fn run(self: Arc<Self>, pixel_map: Arc<PixelMap>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let recorder = Arc::clone(&self);
+            let pixel_map = Arc::clone(&pixel_map);
+            let result = tokio::task::spawn_blocking(move || recorder.record(&pixel_map)).await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to record timelapse {}: {}", self.path.display(), e),
+                Err(e) => println!("Timelapse task failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Checks the header and returns the offset right after the last complete frame
+fn find_end(file: &mut File) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut *file);
+    read_header(&mut reader)?;
+    let mut end = MAGIC.len() as u64 + 1;
+    let mut header = [0u8; FRAME_HEADER_SIZE as usize];
+    while end + FRAME_HEADER_SIZE <= len {
+        reader.seek(SeekFrom::Start(end))?;
+        reader.read_exact(&mut header)?;
+        let frame_end = end + FRAME_HEADER_SIZE + u32_at(&header, 17) as u64;
+        if frame_end > len {
+            break;
+        }
+        end = frame_end;
+    }
+    Ok(end)
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<()> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a timelapse archive"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid(format!("unsupported archive version {}", header[4])));
+    }
+    Ok(())
+}
+
+/// Reads the frames of an archive, starting with the oldest
+pub(crate) struct Frames<R> {
+    reader: R,
+    previous: Option<Frame>,
+}
+
+impl Frames<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Frames::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Frames<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        read_header(&mut reader)?;
+        Ok(Frames {
+            reader,
+            previous: None,
+        })
+    }
+
+    fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0u8; FRAME_HEADER_SIZE as usize];
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let kind = header[0];
+        let time_ms = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let (width, height) = (u32_at(&header, 9), u32_at(&header, 13));
+        let length = u32_at(&header, 17) as u64;
+        let rgba_len = width as u64 * height as u64 * 4;
+        if length > max_payload(rgba_len) {
+            return Err(invalid("frame is larger than its canvas"));
+        }
+        // Grows with the bytes actually read, a corrupt length can't make it
+        // allocate more than the file holds
+        let mut payload = Vec::new();
+        if (&mut self.reader).take(length).read_to_end(&mut payload)? as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let rgba = match kind {
+            KEYFRAME => {
+                // Checked before decoding, so a corrupt size can't make it
+                // allocate more than the payload could possibly hold
+                let qoi = Qoi::decode_header(&payload).map_err(|e| invalid(format!("{:?}", e)))?;
+                if (qoi.width, qoi.height) != (width, height) {
+                    return Err(invalid("keyframe size doesn't match its header"));
+                }
+                if width as u64 * height as u64 > length * QOI_MAX_RUN {
+                    return Err(invalid("keyframe is too small for its size"));
+                }
+                let (qoi, pixels) =
+                    Qoi::decode_alloc(&payload).map_err(|e| invalid(format!("{:?}", e)))?;
+                match qoi.colors.has_alpha() {
+                    true => pixels,
+                    false => pixels
+                        .chunks_exact(3)
+                        .flat_map(|p| [p[0], p[1], p[2], 255])
+                        .collect(),
+                }
+            }
+            XOR_FRAME => {
+                let previous = match &self.previous {
+                    Some(previous) if (previous.width, previous.height) == (width, height) => {
+                        previous
+                    }
+                    _ => return Err(invalid("delta frame without a matching previous frame")),
+                };
+                let xor = match fdeflate::decompress_to_vec_bounded(&payload, previous.rgba.len()) {
+                    Ok(xor) => xor,
+                    Err(BoundedDecompressionError::DecompressionError { inner }) => {
+                        return Err(invalid(format!("{:?}", inner)))
+                    }
+                    Err(BoundedDecompressionError::OutputTooLarge { .. }) => {
+                        return Err(invalid("delta frame has the wrong size"))
+                    }
+                };
+                if xor.len() != previous.rgba.len() {
+                    return Err(invalid("delta frame has the wrong size"));
+                }
+                xor.iter().zip(&previous.rgba).map(|(a, b)| a ^ b).collect()
+            }
+            kind => return Err(invalid(format!("unknown frame kind {}", kind))),
+        };
+        self.previous = Some(Frame {
+            time_ms,
+            width,
+            height,
+            rgba,
+        });
+        Ok(self.previous.clone())
+    }
+}
+
+impl<R: Read> Iterator for Frames<R> {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Runs `pixelrust export-timelapse`, `args` are the arguments after the
+/// subcommand
+pub fn export_command(args: &[String]) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut format = ImageFormat::Png;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", EXPORT_USAGE);
+                std::process::exit(0);
+            }
+            "--format" => args.next().map(String::as_str),
+            arg if arg.starts_with("--format=") => Some(&arg["--format=".len()..]),
+            arg if arg.starts_with('-') => {
+                return Err(format!("Unexpected argument '{}'\n\n{}", arg, EXPORT_USAGE))
+            }
+            path => {
+                paths.push(path);
+                continue;
+            }
+        };
+        format = match value {
+            Some("png") => ImageFormat::Png,
+            Some("qoi") => ImageFormat::Qoi,
+            Some(other) => return Err(format!("Unknown format '{}', use png or qoi", other)),
+            None => return Err("Missing value for --format".to_string()),
+        };
+    }
+    let [archive, dir] = paths[..] else {
+        return Err(EXPORT_USAGE.to_string());
+    };
+
+    let (count, span) = export(Path::new(archive), Path::new(dir), format)?;
+    println!(
+        "Exported {} frames covering {}s to {}",
+        count,
+        span.as_secs(),
+        dir
+    );
+    Ok(())
+}
+
+/// Writes every frame to `dir`. Returns the number of frames and the time
+/// between the first and the last one.
+fn export(archive: &Path, dir: &Path, format: ImageFormat) -> Result<(usize, Duration), String> {
+    let frames = Frames::open(archive)
+        .map_err(|e| format!("Could not read {}: {}", archive.display(), e))?;
+    fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    let mut count = 0;
+    let mut times = None;
+    for frame in frames {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                println!("{} ends with an incomplete frame, skipping it", archive.display());
+                break;
+            }
+            Err(e) => return Err(format!("Invalid frame {} in {}: {}", count, archive.display(), e)),
+        };
+        let extension = if format == ImageFormat::Png { "png" } else { "qoi" };
+        let path = dir.join(format!("frame_{:06}.{}", count, extension));
+        format
+            // The archive doesn't keep the colorspace
+            .encode(frame.width, frame.height, &frame.rgba, false)
+            .and_then(|image| fs::write(&path, image))
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        let first = times.map_or(frame.time_ms, |(first, _)| first);
+        times = Some((first, frame.time_ms));
+        count += 1;
+    }
+    let span = times.map_or(0, |(first, last)| last.saturating_sub(first));
+    Ok((count, Duration::from_millis(span)))
+}
+
+/// Current unix time in milliseconds
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Largest payload a frame of `rgba_len` bytes of pixels can have: QOI needs
+/// up to 5 bytes per pixel plus header and end marker, deflate a few bytes
+/// per block on top of the XORed pixels
+fn max_payload(rgba_len: u64) -> u64 {
+    rgba_len / 4 * 5 + 1024
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribution::Writer;
+    use crate::color::Color;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pixelrust-{}-{}.bin", name, std::process::id()))
+    }
+
+    fn read_all(path: &Path) -> Vec<Frame> {
+        Frames::open(path).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let path = temp_path("timelapse-round-trip");
+        let _ = fs::remove_file(&path);
+        let pixel_map = PixelMap::new(3, 2);
+        let recorder = Recorder::open(&path).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..3u8 {
+            pixel_map.blend_color(i as u32, 1, Color::from_rgb(i, 100, 200), Writer::default());
+            assert!(recorder.record(&pixel_map).unwrap());
+            expected.push(pixel_map.to_image().rgba);
+        }
+        // Nothing changed, nothing to record
+        assert!(!recorder.record(&pixel_map).unwrap());
+        pixel_map.resize(2, 2, Color::black());
+        assert!(recorder.record(&pixel_map).unwrap());
+        expected.push(pixel_map.to_image().rgba);
+        drop(recorder);
+
+        let frames = read_all(&path);
+        let rgba: Vec<Vec<u8>> = frames.iter().map(|frame| frame.rgba.clone()).collect();
+        assert_eq!(rgba, expected);
+        assert_eq!((frames[3].width, frames[3].height), (2, 2));
+        // A keyframe, two deltas and a keyframe for the new size
+        let data = fs::read(&path).unwrap();
+        let mut kinds = Vec::new();
+        let mut at = MAGIC.len() + 1;
+        while at < data.len() {
+            kinds.push(data[at]);
+            at += FRAME_HEADER_SIZE as usize + u32_at(&data, at + 17) as usize;
+        }
+        assert_eq!(kinds, [KEYFRAME, XOR_FRAME, XOR_FRAME, KEYFRAME]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cuts_off_a_truncated_frame() {
+        let path = temp_path("timelapse-truncated");
+        let _ = fs::remove_file(&path);
+        let pixel_map = PixelMap::new(4, 4);
+        let recorder = Recorder::open(&path).unwrap();
+        recorder.record(&pixel_map).unwrap();
+        pixel_map.blend_color(0, 0, Color::from_rgb(255, 0, 0), Writer::default());
+        recorder.record(&pixel_map).unwrap();
+        drop(recorder);
+        let complete = fs::metadata(&path).unwrap().len();
+        // The server died in the middle of the next frame
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[KEYFRAME, 1, 2, 3, 4]).unwrap();
+        drop(file);
+        let frames: Vec<io::Result<Frame>> = Frames::open(&path).unwrap().collect();
+        assert_eq!(frames.len(), 3);
+        let error = frames[2].as_ref().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let recorder = Recorder::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        pixel_map.blend_color(1, 0, Color::from_rgb(0, 255, 0), Writer::default());
+        recorder.record(&pixel_map).unwrap();
+        let frames = read_all(&path);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].rgba, pixel_map.to_image().rgba);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_their_canvas() {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.push(KEYFRAME);
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        let error = Frames::new(&data[..]).unwrap().next().unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_keyframes_too_small_for_their_size() {
+        // A QOI header that claims gigabytes of pixels, but no pixel data
+        let size = 60_000u32;
+        let mut qoi = b"qoif".to_vec();
+        qoi.extend_from_slice(&size.to_be_bytes());
+        qoi.extend_from_slice(&size.to_be_bytes());
+        qoi.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.push(KEYFRAME);
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&(qoi.len() as u32).to_le_bytes());
+        data.extend_from_slice(&qoi);
+        let error = Frames::new(&data[..]).unwrap().next().unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "keyframe is too small for its size");
+    }
+}